@@ -0,0 +1,433 @@
+use std::{collections::HashMap, fs, hash::Hash, path::PathBuf};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use libcosiauthd::{
+    export, protocol::capability, AuthdClient, Failover, FailoverOrder, Field, MatchMode, Pin,
+    Search, Servers, Shell, Trust, WireFormat,
+};
+use tarpc::context;
+
+#[derive(Debug, serde::Deserialize)]
+struct CtlConfig {
+    host: Servers,
+    #[serde(default)]
+    failover: FailoverOrder,
+    cert: String,
+    #[serde(default)]
+    server_name: Option<String>,
+    #[serde(default)]
+    pins: Vec<Pin>,
+    shells_root: String,
+    shells: Vec<Shell>,
+    home_root: String,
+    #[serde(default)]
+    wire_format: WireFormat,
+    /// File holding the admin token, needed for `search` and `attributes`
+    #[serde(default)]
+    admin_token_file: Option<PathBuf>,
+}
+
+/// Command line tool for querying an authd server
+#[derive(Debug, Parser)]
+struct Args {
+    /// Path of the authctl configuration file
+    #[arg(short, long, default_value = "/etc/auth/authctl.toml")]
+    config: PathBuf,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Export the database for machines that can't use nss_cosiauthd
+    Export {
+        #[arg(value_enum)]
+        format: ExportFormat,
+
+        /// Write to this file instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Look up users by name or uid, printed `/etc/passwd` style
+    Passwd {
+        /// Names or uids
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    /// Look up groups by name or gid, printed `/etc/group` style
+    Group {
+        /// Names or gids
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    /// Search users, or groups with --groups. Needs an admin token.
+    Search {
+        pattern: String,
+
+        /// Search groups instead of users
+        #[arg(short, long)]
+        groups: bool,
+
+        #[arg(short, long, value_enum, default_value = "substring")]
+        mode: SearchMode,
+
+        /// Fields to match; may be repeated. Defaults to name and gecos.
+        #[arg(short, long = "field", value_enum)]
+        fields: Vec<SearchField>,
+
+        #[arg(short, long)]
+        ignore_case: bool,
+
+        /// Most results to show
+        #[arg(short, long, default_value_t = 20)]
+        limit: u32,
+    },
+    /// Show the attributes of a user, or a group with --group. Needs an admin token.
+    Attributes {
+        name: String,
+
+        /// Look up a group instead of a user
+        #[arg(short, long)]
+        group: bool,
+    },
+    /// Check that the server is up and show what it is serving
+    Status,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum SearchMode {
+    Prefix,
+    Substring,
+    Regex,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SearchField {
+    Name,
+    Gecos,
+    /// Group membership
+    Member,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    /// `/etc/passwd` style
+    Passwd,
+    /// `/etc/group` style
+    Group,
+    /// JSON dump of every user and group
+    Json,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    let contents = fs::read_to_string(&args.config)?;
+    let config = toml::from_str::<CtlConfig>(&contents)?;
+    config
+        .host
+        .check_server_name(config.server_name.as_deref())?;
+
+    match args.command {
+        Command::Export { format, output } => run_export(&config, format, output).await,
+        Command::Passwd { keys } => run_passwd(&config, keys).await,
+        Command::Group { keys } => run_group(&config, keys).await,
+        Command::Search {
+            pattern,
+            groups,
+            mode,
+            fields,
+            ignore_case,
+            limit,
+        } => {
+            let mode = match mode {
+                SearchMode::Prefix => MatchMode::Prefix,
+                SearchMode::Substring => MatchMode::Substring,
+                SearchMode::Regex => MatchMode::Regex,
+            };
+            let fields = if fields.is_empty() {
+                vec![Field::Name, Field::Gecos]
+            } else {
+                fields
+                    .into_iter()
+                    .map(|field| match field {
+                        SearchField::Name => Field::Name,
+                        SearchField::Gecos => Field::Gecos,
+                        SearchField::Member => Field::Member,
+                    })
+                    .collect()
+            };
+            let query = Search {
+                pattern,
+                mode,
+                fields,
+                ignore_case,
+                limit,
+            };
+            run_search(&config, query, groups).await
+        }
+        Command::Attributes { name, group } => run_attributes(&config, name, group).await,
+        Command::Status => run_status(&config).await,
+    }
+}
+
+/// Connects to a server, which must advertise `capability` if the command needs one
+async fn connect(config: &CtlConfig, capability: Option<&str>) -> anyhow::Result<AuthdClient> {
+    let trust = Trust::load(&config.cert, config.pins.clone())?;
+    let failover = Failover::new(config.host.clone(), config.failover)?;
+    let connected = failover
+        .connect(&trust, config.server_name.as_deref(), config.wire_format)
+        .await?;
+
+    if let Some(capability) = capability {
+        if !connected.server.supports(capability) {
+            anyhow::bail!(
+                "{} ({}, protocol version {}) doesn't support {}; upgrade it",
+                connected.addr,
+                connected.server.software,
+                connected.server.protocol_version,
+                capability
+            );
+        }
+    }
+
+    Ok(connected.client)
+}
+
+async fn run_export(
+    config: &CtlConfig,
+    format: ExportFormat,
+    output: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let client = connect(config, None).await?;
+
+    let rendered = match format {
+        ExportFormat::Passwd => {
+            let users = client.get_all_passwd(context::current()).await?;
+            export::passwd_file(
+                &users,
+                &config.home_root,
+                &config.shells_root,
+                &config.shells,
+            )?
+        }
+        ExportFormat::Group => {
+            let groups = client.get_all_groups(context::current()).await?;
+            export::group_file(&groups)?
+        }
+        ExportFormat::Json => {
+            let users = client.get_all_passwd(context::current()).await?;
+            let groups = client.get_all_groups(context::current()).await?;
+            export::json_dump(&users, &groups)?
+        }
+    };
+
+    match output {
+        Some(path) => fs::write(path, rendered)?,
+        None => print!("{}", rendered),
+    }
+
+    Ok(())
+}
+
+/// Looks all the users up in two requests, one by name and one by uid
+async fn run_passwd(config: &CtlConfig, keys: Vec<String>) -> anyhow::Result<()> {
+    let client = connect(config, Some(capability::BATCH)).await?;
+    let (names, uids) = split_keys(&keys);
+
+    let by_name = client
+        .get_passwds_by_name(context::current(), names.clone())
+        .await?;
+    let by_uid = client
+        .get_passwds_by_uid(context::current(), uids.clone())
+        .await?;
+
+    let (users, missing) = collate(&keys, zip(names, by_name)?, zip(uids, by_uid)?);
+    print!(
+        "{}",
+        export::passwd_file(
+            &users,
+            &config.home_root,
+            &config.shells_root,
+            &config.shells
+        )?
+    );
+
+    if !missing.is_empty() {
+        anyhow::bail!("no such user: {}", missing.join(", "));
+    }
+    Ok(())
+}
+
+/// Looks all the groups up in two requests, one by name and one by gid
+async fn run_group(config: &CtlConfig, keys: Vec<String>) -> anyhow::Result<()> {
+    let client = connect(config, Some(capability::BATCH)).await?;
+    let (names, gids) = split_keys(&keys);
+
+    let by_name = client
+        .get_groups_by_name(context::current(), names.clone())
+        .await?;
+    let by_gid = client
+        .get_groups_by_gid(context::current(), gids.clone())
+        .await?;
+
+    let (groups, missing) = collate(&keys, zip(names, by_name)?, zip(gids, by_gid)?);
+    print!("{}", export::group_file(&groups)?);
+
+    if !missing.is_empty() {
+        anyhow::bail!("no such group: {}", missing.join(", "));
+    }
+    Ok(())
+}
+
+/// Sorts keys from the command line into names and numeric ids
+fn split_keys(keys: &[String]) -> (Vec<String>, Vec<u32>) {
+    let mut names = Vec::new();
+    let mut ids = Vec::new();
+
+    for key in keys {
+        match key.parse() {
+            Ok(id) => ids.push(id),
+            Err(_) => names.push(key.clone()),
+        }
+    }
+
+    (names, ids)
+}
+
+/// Pairs the keys of a batch lookup with their answers
+fn zip<K: Eq + Hash, T>(
+    keys: Vec<K>,
+    found: Vec<Option<T>>,
+) -> anyhow::Result<HashMap<K, Option<T>>> {
+    if keys.len() != found.len() {
+        anyhow::bail!("server answered {} of {} lookups", found.len(), keys.len());
+    }
+    Ok(keys.into_iter().zip(found).collect())
+}
+
+/// Puts the answers back in the order the keys were given, returning what was found and the keys
+/// that weren't
+fn collate<T: Clone>(
+    keys: &[String],
+    by_name: HashMap<String, Option<T>>,
+    by_id: HashMap<u32, Option<T>>,
+) -> (Vec<T>, Vec<String>) {
+    let mut found = Vec::new();
+    let mut missing = Vec::new();
+
+    for key in keys {
+        let entry = match key.parse::<u32>() {
+            Ok(id) => by_id.get(&id),
+            Err(_) => by_name.get(key),
+        };
+        match entry.cloned().flatten() {
+            Some(entry) => found.push(entry),
+            None => missing.push(key.clone()),
+        }
+    }
+
+    (found, missing)
+}
+
+/// Reads the admin token `command` needs
+fn admin_token(config: &CtlConfig, command: &str) -> anyhow::Result<String> {
+    let path = config
+        .admin_token_file
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("{} needs admin_token_file in the config", command))?;
+    Ok(fs::read_to_string(path)?.trim().to_string())
+}
+
+async fn run_search(config: &CtlConfig, query: Search, groups: bool) -> anyhow::Result<()> {
+    let token = admin_token(config, "search")?;
+
+    let client = connect(config, Some(capability::SEARCH)).await?;
+
+    if groups {
+        let found = client
+            .search_groups(context::current(), token, query)
+            .await??;
+        print!("{}", export::group_file(&found)?);
+    } else {
+        let found = client
+            .search_users(context::current(), token, query)
+            .await??;
+        print!(
+            "{}",
+            export::passwd_file(
+                &found,
+                &config.home_root,
+                &config.shells_root,
+                &config.shells
+            )?
+        );
+    }
+
+    Ok(())
+}
+
+async fn run_attributes(config: &CtlConfig, name: String, group: bool) -> anyhow::Result<()> {
+    let token = admin_token(config, "attributes")?;
+
+    let client = connect(config, Some(capability::ATTRIBUTES)).await?;
+
+    let attributes = if group {
+        client
+            .get_group_attributes(context::current(), token, name.clone())
+            .await??
+    } else {
+        client
+            .get_user_attributes(context::current(), token, name.clone())
+            .await??
+    };
+    let Some(attributes) = attributes else {
+        anyhow::bail!("{} not found", name);
+    };
+
+    for (name, value) in attributes.all() {
+        println!("{}: {}", name, value);
+    }
+
+    Ok(())
+}
+
+async fn run_status(config: &CtlConfig) -> anyhow::Result<()> {
+    let client = connect(config, None).await?;
+    let status = client.status(context::current()).await?;
+
+    println!("version:     {}", status.version);
+    println!("uptime:      {}s", status.uptime_secs);
+
+    if let Some(upstream) = &status.upstream {
+        println!("upstream:    {}", upstream.host);
+        println!("connected:   {}", upstream.connected);
+        if let Some(age) = upstream.cache_age_secs {
+            println!("cache age:   {}s", age);
+        }
+    }
+
+    if let Some(replication) = &status.replication {
+        println!("primary:     {}", replication.primary);
+        println!("following:   {}", replication.connected);
+        match replication.lag_secs {
+            Some(lag) => println!("lag:         {}s", lag),
+            None => println!("lag:         never synced"),
+        }
+    }
+
+    match &status.database {
+        Some(db) => {
+            println!("generation:  {}", db.generation);
+            println!("config hash: {}", db.config_hash);
+            println!("last reload: {}", db.last_reload);
+            println!("users:       {}", db.users);
+            println!("groups:      {}", db.groups);
+        }
+        None => anyhow::bail!("upstream database unavailable"),
+    }
+
+    Ok(())
+}
@@ -1,17 +1,283 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::SocketAddr,
+};
 
-use libcosiauthd::{Group, User};
+use anyhow::bail;
+use libcosiauthd::{
+    logging::LogConfig, Attributes, Group, Pin, Servers, SocketName, User, Value, WireFormat,
+};
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    groups::{self, Nested},
+    rules::Rule,
+};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
-    pub groups: Vec<Group>,
-    pub users: Vec<User>,
+    #[serde(default)]
+    pub groups: Vec<GroupEntry>,
+    #[serde(default)]
+    pub users: Vec<UserEntry>,
+    /// Groups whose members are the users matching a rule, worked out again on every reload
+    #[serde(default)]
+    pub dynamic_groups: Vec<DynamicGroup>,
     pub cert: String,
     pub key: String,
+    /// Address to accept clients on
+    #[serde(default = "default_listen_addr")]
+    pub listen_addr: SocketAddr,
+    /// Follow another authd instead of serving the users and groups defined here
+    #[serde(default)]
+    pub replicate: Option<ReplicateConfig>,
+    /// Read once at startup
+    #[serde(default)]
+    pub log: LogConfig,
+    /// Address to serve Prometheus metrics on. Metrics are disabled when unset.
+    #[serde(default)]
+    pub metrics_addr: Option<SocketAddr>,
+    /// How long in-flight requests get to finish when shutting down
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout_secs: u64,
+    /// How long a client gets to finish the TLS handshake
+    #[serde(default = "default_handshake_timeout")]
+    pub handshake_timeout_secs: u64,
+    /// Connections are closed after this long without a request
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout_secs: u64,
+    /// Most connections a single client address may have open at once
+    #[serde(default = "default_max_connections_per_ip")]
+    pub max_connections_per_ip: usize,
+    /// Most users or groups sent in one page of `list_passwd` or `list_groups`
+    #[serde(default = "default_max_page_size")]
+    pub max_page_size: u32,
+    /// Most keys looked up by one of the batch lookups
+    #[serde(default = "default_max_batch_size")]
+    pub max_batch_size: u32,
+    /// SHA-256 hashes, in hex, of the tokens admin clients present to search users and groups and
+    /// read their attributes. Nobody can while this is empty.
+    #[serde(default)]
+    pub admin_tokens: Vec<String>,
+    /// Most users or groups sent back by one search
+    #[serde(default = "default_max_search_results")]
+    pub max_search_results: u32,
+}
+
+/// A user as written in the config
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserEntry {
+    #[serde(flatten)]
+    pub user: User,
+    /// Only sent to admin clients, and tested by dynamic group rules
+    #[serde(default, deserialize_with = "strict_attributes")]
+    pub attributes: Attributes,
+}
+
+/// A group as written in the config
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupEntry {
+    #[serde(flatten)]
+    pub group: Group,
+    /// Groups whose members also belong to this one. Clients are served `members` with theirs
+    /// already added.
+    #[serde(default)]
+    pub includes: Vec<String>,
+    /// Only sent to admin clients
+    #[serde(default, deserialize_with = "strict_attributes")]
+    pub attributes: Attributes,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DynamicGroup {
+    pub name: String,
+    pub gid: u32,
+    pub rule: Rule,
+    /// Only sent to admin clients
+    #[serde(default, deserialize_with = "strict_attributes")]
+    pub attributes: Attributes,
+}
+
+/// [`Attributes`] as written in the config. Unlike on the wire, where fields from newer versions
+/// are skipped, an unknown field here is a misspelling and rejected.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StrictAttributes {
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default)]
+    student_id: Option<String>,
+    #[serde(default)]
+    class_year: Option<i64>,
+    #[serde(default)]
+    display_name: Option<String>,
+    #[serde(default)]
+    pronouns: Option<String>,
+    #[serde(default)]
+    expiry_note: Option<String>,
+    #[serde(default)]
+    custom: BTreeMap<String, Value>,
+}
+
+fn strict_attributes<'de, D>(deserializer: D) -> Result<Attributes, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let strict = StrictAttributes::deserialize(deserializer)?;
+    // No `..`, so a field added to Attributes must be added here too
+    Ok(Attributes {
+        email: strict.email,
+        student_id: strict.student_id,
+        class_year: strict.class_year,
+        display_name: strict.display_name,
+        pronouns: strict.pronouns,
+        expiry_note: strict.expiry_note,
+        custom: strict.custom,
+    })
+}
+
+/// Where a replica gets its database from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicateConfig {
+    pub primary: SocketName,
+    /// The primary's certificate or its CA(s), DER or PEM
+    pub cert: String,
+    /// Name to check the primary's certificate against, instead of the one in `primary`
+    #[serde(default)]
+    pub server_name: Option<String>,
+    #[serde(default)]
+    pub pins: Vec<Pin>,
+    #[serde(default)]
+    pub wire_format: WireFormat,
+}
+
+fn default_listen_addr() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 8765))
+}
+
+fn default_shutdown_timeout() -> u64 {
+    10
+}
+
+fn default_handshake_timeout() -> u64 {
+    10
+}
+
+fn default_idle_timeout() -> u64 {
+    300
+}
+
+fn default_max_connections_per_ip() -> usize {
+    64
+}
+
+fn default_max_page_size() -> u32 {
+    1000
+}
+
+fn default_max_batch_size() -> u32 {
+    1000
+}
+
+fn default_max_search_results() -> u32 {
+    100
 }
 
 impl Config {
+    /// Parses and validates the contents of a config file
+    pub(crate) fn parse(contents: &str) -> anyhow::Result<Self> {
+        let config = toml::from_str::<Config>(contents)?;
+
+        if !config.check_dup() {
+            bail!("config has duplicate ids");
+        }
+
+        if config.replicate.is_some()
+            && !(config.users.is_empty()
+                && config.groups.is_empty()
+                && config.dynamic_groups.is_empty())
+        {
+            bail!("a replica gets its users and groups from the primary and can't define its own");
+        }
+
+        if let Some(replicate) = &config.replicate {
+            Servers::from(replicate.primary.clone())
+                .check_server_name(replicate.server_name.as_deref())?;
+        }
+
+        // Includes and attribute lookups find groups by name, so each must name exactly one
+        let mut names = HashSet::new();
+        let all_names = config
+            .groups
+            .iter()
+            .map(|entry| &entry.group.name)
+            .chain(config.dynamic_groups.iter().map(|group| &group.name));
+        for name in all_names {
+            if !names.insert(name) {
+                bail!("more than one group is called {}", name);
+            }
+        }
+
+        groups::check(&config.all_groups())?;
+
+        Ok(config)
+    }
+
+    /// The users defined here, as served to clients
+    pub(crate) fn all_users(&self) -> Vec<User> {
+        self.users.iter().map(|entry| entry.user.clone()).collect()
+    }
+
+    /// The groups defined here, followed by the dynamic groups with the users currently matching
+    /// their rules as members. Static groups may include dynamic ones, so nesting is expanded
+    /// afterwards with [`groups::expand`].
+    pub(crate) fn all_groups(&self) -> Vec<Nested> {
+        let dynamic = self.dynamic_groups.iter().map(|group| Nested {
+            group: Group {
+                name: group.name.clone(),
+                gid: group.gid,
+                members: self
+                    .users
+                    .iter()
+                    .filter(|entry| group.rule.matches(&entry.user, &entry.attributes))
+                    .map(|entry| entry.user.name.clone())
+                    .collect(),
+            },
+            includes: Vec::new(),
+        });
+
+        self.groups
+            .iter()
+            .map(|entry| Nested {
+                group: entry.group.clone(),
+                includes: entry.includes.clone(),
+            })
+            .chain(dynamic)
+            .collect()
+    }
+
+    /// The attributes of the user called `name`, if there is one
+    pub(crate) fn user_attributes(&self, name: &str) -> Option<&Attributes> {
+        self.users
+            .iter()
+            .find(|entry| entry.user.name == name)
+            .map(|entry| &entry.attributes)
+    }
+
+    /// The attributes of the group called `name`, static or dynamic, if there is one
+    pub(crate) fn group_attributes(&self, name: &str) -> Option<&Attributes> {
+        self.groups
+            .iter()
+            .find(|entry| entry.group.name == name)
+            .map(|entry| &entry.attributes)
+            .or_else(|| {
+                self.dynamic_groups
+                    .iter()
+                    .find(|group| group.name == name)
+                    .map(|group| &group.attributes)
+            })
+    }
+
     /// Verifies all defined ids and gids have no overlap.
     /// Prints the first reported error to stderr
     pub(crate) fn check_dup(&self) -> bool {
@@ -33,8 +299,16 @@ impl Config {
         let groups = self
             .groups
             .iter()
-            .map(|g| GroupOrUser::Group(&g.name, g.gid));
-        let users = self.users.iter().map(|u| GroupOrUser::User(&u.name, u.id));
+            .map(|g| GroupOrUser::Group(&g.group.name, g.group.gid))
+            .chain(
+                self.dynamic_groups
+                    .iter()
+                    .map(|g| GroupOrUser::Group(&g.name, g.gid)),
+            );
+        let users = self
+            .users
+            .iter()
+            .map(|u| GroupOrUser::User(&u.user.name, u.user.id));
 
         for gu in groups.chain(users) {
             let id = gu.get_id();
@@ -63,3 +337,96 @@ impl Config {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn misspelled_attributes_are_rejected() {
+        let entry: UserEntry = toml::from_str(
+            r#"
+            name = "alice"
+            id = 1001
+
+            [attributes]
+            class_year = 2027
+            custom = { role = "mentor" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(entry.attributes.class_year, Some(2027));
+        assert_eq!(
+            entry.attributes.get("role"),
+            Some(Value::String("mentor".to_string()))
+        );
+
+        let err = toml::from_str::<UserEntry>(
+            r#"
+            name = "alice"
+            id = 1001
+
+            [attributes]
+            class_yaer = 2027
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("class_yaer"), "{}", err);
+    }
+
+    #[test]
+    fn group_names_must_be_unique() {
+        let config = |dynamic_name: &str| {
+            format!(
+                r#"
+                cert = "cert.pem"
+                key = "key.pem"
+
+                [[groups]]
+                name = "wheel"
+                gid = 10
+
+                [[groups]]
+                name = "staff"
+                gid = 50
+                includes = ["mentors"]
+
+                [[dynamic_groups]]
+                name = "{}"
+                gid = 4027
+                rule = "class_year = 2027"
+                "#,
+                dynamic_name
+            )
+        };
+
+        Config::parse(&config("mentors")).unwrap();
+
+        let err = Config::parse(&config("wheel")).unwrap_err();
+        assert_eq!(err.to_string(), "more than one group is called wheel");
+
+        let twice = config("mentors").replace("name = \"staff\"", "name = \"wheel\"");
+        let err = Config::parse(&twice).unwrap_err();
+        assert_eq!(err.to_string(), "more than one group is called wheel");
+    }
+
+    #[test]
+    fn primary_by_address_needs_a_server_name() {
+        let config = |extra: &str| {
+            format!(
+                r#"
+                cert = "cert.pem"
+                key = "key.pem"
+
+                [replicate]
+                primary = "192.0.2.1:8765"
+                cert = "primary.pem"
+                {}
+                "#,
+                extra
+            )
+        };
+
+        assert!(Config::parse(&config("")).is_err());
+        Config::parse(&config("server_name = \"auth.test\"")).unwrap();
+    }
+}
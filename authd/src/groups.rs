@@ -0,0 +1,203 @@
+//! Groups that include other groups.
+//!
+//! A group's `includes` lists groups whose members also belong to it, directly or through groups
+//! those include in turn. `includes` only exists in the config. Clients never see the nesting:
+//! authd serves every group with its full, expanded list of members, so lookups, listings and the
+//! `getgrent` scans glibc uses for `initgroups` all see transitive memberships.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::bail;
+use libcosiauthd::Group;
+
+/// A group as defined in the config, before the groups it includes are expanded
+#[derive(Debug, Clone)]
+pub struct Nested {
+    pub group: Group,
+    pub includes: Vec<String>,
+}
+
+impl Nested {
+    fn name(&self) -> &str {
+        &self.group.name
+    }
+}
+
+/// Checks that every included group exists and that no group includes itself, however indirectly
+pub fn check(groups: &[Nested]) -> anyhow::Result<()> {
+    let by_name: HashMap<&str, &Nested> = groups.iter().map(|g| (g.name(), g)).collect();
+
+    for group in groups {
+        for included in &group.includes {
+            if !by_name.contains_key(included.as_str()) {
+                bail!("group {} includes unknown group {}", group.name(), included);
+            }
+        }
+    }
+
+    // Depth-first search for a path leading back to a group already on it
+    fn visit<'a>(
+        group: &'a Nested,
+        by_name: &HashMap<&str, &'a Nested>,
+        path: &mut Vec<&'a str>,
+        done: &mut HashSet<&'a str>,
+    ) -> anyhow::Result<()> {
+        if let Some(start) = path.iter().position(|name| *name == group.name()) {
+            let mut cycle = path[start..].to_vec();
+            cycle.push(group.name());
+            bail!("groups include each other: {}", cycle.join(" -> "));
+        }
+        if done.contains(group.name()) {
+            return Ok(());
+        }
+
+        path.push(group.name());
+        for included in &group.includes {
+            visit(by_name[included.as_str()], by_name, path, done)?;
+        }
+        path.pop();
+        done.insert(group.name());
+
+        Ok(())
+    }
+
+    let mut done = HashSet::new();
+    for group in groups {
+        visit(group, &by_name, &mut Vec::new(), &mut done)?;
+    }
+
+    Ok(())
+}
+
+/// Returns `groups` as served, with every group's members followed by the members of the groups
+/// it includes, each member listed once. Unknown groups and cycles are skipped, though [`check`]
+/// rejects configs with either.
+pub fn expand(groups: &[Nested]) -> Vec<Group> {
+    let by_name: HashMap<&str, &Nested> = groups.iter().map(|g| (g.name(), g)).collect();
+
+    groups
+        .iter()
+        .map(|group| {
+            let mut members = Vec::new();
+            let mut seen_members = HashSet::new();
+            let mut seen_groups = HashSet::new();
+            let mut pending = vec![group];
+
+            while let Some(next) = pending.pop() {
+                if !seen_groups.insert(next.name()) {
+                    continue;
+                }
+                for member in &next.group.members {
+                    if seen_members.insert(member.as_str()) {
+                        members.push(member.clone());
+                    }
+                }
+                // Reversed so included groups are expanded in the order they are listed
+                for included in next.includes.iter().rev() {
+                    if let Some(included) = by_name.get(included.as_str()) {
+                        pending.push(included);
+                    }
+                }
+            }
+
+            Group {
+                members,
+                ..group.group.clone()
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(name: &str, gid: u32, members: &[&str], includes: &[&str]) -> Nested {
+        Nested {
+            group: Group {
+                name: name.to_string(),
+                gid,
+                members: members.iter().map(|m| m.to_string()).collect(),
+            },
+            includes: includes.iter().map(|i| i.to_string()).collect(),
+        }
+    }
+
+    fn members<'a>(groups: &'a [Group], name: &str) -> Vec<&'a str> {
+        groups
+            .iter()
+            .find(|g| g.name == name)
+            .unwrap()
+            .members
+            .iter()
+            .map(String::as_str)
+            .collect()
+    }
+
+    #[test]
+    fn rejects_including_itself() {
+        let groups = [group("wheel", 10, &["alice"], &["wheel"])];
+        let err = check(&groups).unwrap_err().to_string();
+        assert_eq!(err, "groups include each other: wheel -> wheel");
+    }
+
+    #[test]
+    fn rejects_indirect_cycles() {
+        let groups = [
+            group("a", 1, &[], &["b"]),
+            group("b", 2, &[], &["c"]),
+            group("c", 3, &[], &["a"]),
+        ];
+        let err = check(&groups).unwrap_err().to_string();
+        assert_eq!(err, "groups include each other: a -> b -> c -> a");
+    }
+
+    #[test]
+    fn rejects_unknown_includes() {
+        let groups = [group("staff", 1, &[], &["mentors"])];
+        let err = check(&groups).unwrap_err().to_string();
+        assert_eq!(err, "group staff includes unknown group mentors");
+    }
+
+    #[test]
+    fn diamonds_list_each_member_once() {
+        // all includes left and right, which both include base
+        let groups = [
+            group("all", 1, &["dana"], &["left", "right"]),
+            group("left", 2, &["alice", "bob"], &["base"]),
+            group("right", 3, &["bob", "carol"], &["base"]),
+            group("base", 4, &["alice", "erin"], &[]),
+        ];
+        check(&groups).unwrap();
+
+        let expanded = expand(&groups);
+        assert_eq!(
+            members(&expanded, "all"),
+            ["dana", "alice", "bob", "erin", "carol"]
+        );
+        assert_eq!(
+            members(&expanded, "right"),
+            ["bob", "carol", "alice", "erin"]
+        );
+    }
+
+    #[test]
+    fn expands_depth_first_in_listed_order() {
+        let groups = [
+            group("top", 1, &["t"], &["first", "second"]),
+            group("first", 2, &["f"], &["nested"]),
+            group("second", 3, &["s"], &[]),
+            group("nested", 4, &["n"], &[]),
+        ];
+        check(&groups).unwrap();
+
+        let expanded = expand(&groups);
+        assert_eq!(members(&expanded, "top"), ["t", "f", "n", "s"]);
+        assert_eq!(members(&expanded, "first"), ["f", "n"]);
+        assert_eq!(members(&expanded, "nested"), ["n"]);
+
+        // Only members change
+        let top = expanded.iter().find(|g| g.name == "top").unwrap();
+        assert_eq!(top.gid, 1);
+    }
+}
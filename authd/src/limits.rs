@@ -0,0 +1,127 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{Future, Sink, Stream};
+use pin_project::pin_project;
+use tokio::time::{sleep, Instant, Sleep};
+
+/// Tracks how many connections each client address has open
+#[derive(Debug, Default)]
+pub struct ConnectionLimiter {
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl ConnectionLimiter {
+    /// Reserves a connection slot for `ip`, or returns `None` if it already has `max` connections.
+    /// The slot is released when the returned guard is dropped.
+    pub fn try_acquire(self: &Arc<Self>, ip: IpAddr, max: usize) -> Option<ConnectionSlot> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(ip).or_default();
+
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+
+        Some(ConnectionSlot {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+}
+
+/// A connection counted against its client's limit
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut open = self.limiter.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Wraps a transport so its read half ends when the client hasn't sent anything for `timeout`.
+///
+/// Like [`crate::shutdown::Draining`], requests already in flight still get their responses.
+#[pin_project]
+pub struct IdleTimeout<T> {
+    #[pin]
+    inner: T,
+    #[pin]
+    sleep: Sleep,
+    timeout: Duration,
+    expired: bool,
+}
+
+impl<T> IdleTimeout<T> {
+    pub fn new(inner: T, timeout: Duration) -> Self {
+        Self {
+            inner,
+            sleep: sleep(timeout),
+            timeout,
+            expired: false,
+        }
+    }
+}
+
+impl<T: Stream> Stream for IdleTimeout<T> {
+    type Item = T::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+
+        if *this.expired {
+            return Poll::Ready(None);
+        }
+
+        match this.inner.poll_next(cx) {
+            Poll::Ready(item) => {
+                this.sleep.as_mut().reset(Instant::now() + *this.timeout);
+                Poll::Ready(item)
+            }
+            Poll::Pending => {
+                if this.sleep.poll(cx).is_ready() {
+                    *this.expired = true;
+                    Poll::Ready(None)
+                } else {
+                    Poll::Pending
+                }
+            }
+        }
+    }
+}
+
+impl<T: Sink<I>, I> Sink<I> for IdleTimeout<T> {
+    type Error = T::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: I) -> Result<(), Self::Error> {
+        self.project().inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_close(cx)
+    }
+}
@@ -1,78 +1,220 @@
 mod config;
+mod groups;
+mod limits;
+mod metrics;
+mod replica;
 mod rpc;
-
-use crate::{config::Config, rpc::AuthdSession};
-
-use libcosiauthd::Authd;
-use rustls::{Certificate, PrivateKey};
-use std::{
-    fs,
-    net::{IpAddr, Ipv4Addr},
-    process::exit,
-    sync::Arc,
+mod rules;
+mod shutdown;
+mod state;
+mod tls;
+
+use crate::{
+    limits::{ConnectionLimiter, IdleTimeout},
+    rpc::AuthdSession,
+    shutdown::Draining,
+    state::State,
+    tls::CertResolver,
 };
-use tarpc::{
-    server::{BaseChannel, Channel},
-    tokio_serde::formats::Json,
+
+use anyhow::Context;
+use libcosiauthd::{logging, wire, Authd};
+use sd_notify::NotifyState;
+use std::{process::exit, sync::Arc, time::Duration};
+use tarpc::server::{BaseChannel, Channel};
+use tokio::{
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+    task::JoinSet,
 };
-use tokio::net::TcpListener;
 use tokio_rustls::TlsAcceptor;
-use tracing::Level;
-use tracing_subscriber::FmtSubscriber;
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::TRACE)
-        .finish();
+const CONFIG_PATH: &str = "/etc/auth/authd.toml";
 
-    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+/// Bounds for the delay after a failed `accept`, e.g. when out of file descriptors
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
 
-    listen_server().await
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    // The config path may be given as the first argument
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| CONFIG_PATH.to_string());
+    let state = match State::load(path) {
+        Ok(state) => Arc::new(state),
+        Err(err) => {
+            eprintln!("{:#}", err);
+            exit(1);
+        }
+    };
+
+    logging::init(&state.config().log, logging::Target::Daemon, std::io::stdout)?;
+
+    listen_server(state).await
 }
 
 /// Hosts an authd server
-async fn listen_server() -> anyhow::Result<()> {
-    let contents = fs::read_to_string("/etc/auth/authd.toml").expect("read config");
-    let config = Arc::new(toml::from_str::<Config>(&contents).expect("parse config"));
+async fn listen_server(state: Arc<State>) -> anyhow::Result<()> {
+    let config = state.config();
+
+    let certs = CertResolver::load(&config).context("loading the TLS certificate")?;
+    tokio::spawn(certs.clone().watch(state.clone()));
 
-    if !config.check_dup() {
-        exit(1);
+    if let Some(metrics_addr) = config.metrics_addr {
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(metrics_addr).await {
+                tracing::error!("metrics server failed: {:#}", err);
+            }
+        });
+    }
+
+    tokio::spawn(reload_on_hangup(state.clone(), certs.clone()));
+
+    if state.is_replica() {
+        tokio::spawn(replica::follow(state.clone()));
+
+        // Serving an empty database would tell clients every user is gone
+        tracing::info!("waiting for the first sync with the primary");
+        let mut generation = state.subscribe();
+        while *generation.borrow_and_update() == 0 {
+            generation.changed().await?;
+        }
     }
 
-    let server_addr = (IpAddr::V4(Ipv4Addr::UNSPECIFIED), 8765);
+    let server_addr = config.listen_addr;
 
     let tls_config = Arc::new(
         rustls::ServerConfig::builder()
             .with_safe_defaults()
             .with_no_client_auth()
-            .with_single_cert(
-                vec![Certificate(std::fs::read(&config.cert).expect("read cert"))],
-                PrivateKey(std::fs::read(&config.key).expect("read key")),
-            )?,
+            .with_cert_resolver(certs),
     );
-
-    let tls_config = tls_config.clone();
     let acceptor: TlsAcceptor = tls_config.into();
 
     let listener = TcpListener::bind(&server_addr).await.expect("tcp bind");
 
     tracing::info!("listening on {:?}", server_addr);
 
-    loop {
-        let acceptor = acceptor.clone();
-        let (stream, peer_addr) = listener.accept().await.expect("tcp accept");
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    let limiter = Arc::new(ConnectionLimiter::default());
+    let mut backoff = MIN_ACCEPT_BACKOFF;
 
-        let cloned = config.clone();
+    let shutdown = shutdown::wait_for_signal();
+    tokio::pin!(shutdown);
 
-        tokio::spawn(async move {
-            let stream = acceptor.accept(stream).await.expect("accepting tls");
-            let tport = tarpc::serde_transport::Transport::from((stream, Json::default()));
+    let _ = sd_notify::notify(false, &[NotifyState::Ready]);
+
+    loop {
+        let accepted = tokio::select! {
+            _ = &mut shutdown => break,
+            // Reap finished connections so the set doesn't grow forever
+            Some(_) = connections.join_next() => continue,
+            accepted = listener.accept() => accepted,
+        };
+
+        let (stream, peer_addr) = match accepted {
+            Ok(accepted) => {
+                backoff = MIN_ACCEPT_BACKOFF;
+                accepted
+            }
+            Err(err) => {
+                tracing::error!("tcp accept failed, retrying in {:?}: {}", backoff, err);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                continue;
+            }
+        };
+
+        let config = state.config();
+        let Some(slot) = limiter.try_acquire(peer_addr.ip(), config.max_connections_per_ip) else {
+            tracing::warn!(
+                "refusing {:?}: too many connections from that address",
+                peer_addr
+            );
+            continue;
+        };
+
+        let handshake_timeout = Duration::from_secs(config.handshake_timeout_secs);
+        let idle_timeout = Duration::from_secs(config.idle_timeout_secs);
+        let acceptor = acceptor.clone();
+        let cloned = state.clone();
+        let shutdown_rx = shutdown_rx.clone();
+
+        connections.spawn(async move {
+            let _slot = slot;
+            let handshake = async {
+                let stream = acceptor.accept(stream).await?;
+                wire::negotiate(stream).await
+            };
+            let (stream, format) = match tokio::time::timeout(handshake_timeout, handshake).await {
+                Ok(Ok(negotiated)) => negotiated,
+                Ok(Err(err)) => {
+                    metrics::HANDSHAKE_FAILURES.inc();
+                    tracing::warn!("handshake with {:?} failed: {}", peer_addr, err);
+                    return;
+                }
+                Err(_) => {
+                    metrics::HANDSHAKE_FAILURES.inc();
+                    tracing::warn!("handshake with {:?} timed out", peer_addr);
+                    return;
+                }
+            };
+            let tport = wire::transport(stream, format);
+            let tport = Draining::new(IdleTimeout::new(tport, idle_timeout), shutdown_rx);
             let channel = BaseChannel::with_defaults(tport);
             let session = AuthdSession::new(cloned);
 
-            tracing::info!("new connection: {:?}", peer_addr);
+            tracing::info!("new connection: {:?} ({})", peer_addr, format);
+            metrics::TLS_CONNECTIONS.inc();
             channel.execute(session.serve()).await;
+            metrics::TLS_CONNECTIONS.dec();
+            tracing::info!("closed connection: {:?}", peer_addr);
         });
     }
+
+    let _ = sd_notify::notify(false, &[NotifyState::Stopping]);
+    drop(listener);
+
+    let deadline = Duration::from_secs(state.config().shutdown_timeout_secs);
+    tracing::info!(
+        "shutting down, draining {} connections for up to {:?}",
+        connections.len(),
+        deadline
+    );
+
+    let _ = shutdown_tx.send(true);
+    let drained = tokio::time::timeout(deadline, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+
+    if drained.is_err() {
+        tracing::warn!(
+            "{} connections still busy after {:?}, closing them",
+            connections.len(),
+            deadline
+        );
+        connections.shutdown().await;
+    }
+
+    Ok(())
+}
+
+/// Reloads the config, and the certificate if it changed, every time authd receives SIGHUP
+async fn reload_on_hangup(state: Arc<State>, certs: Arc<CertResolver>) {
+    let mut hangup = signal(SignalKind::hangup()).expect("installing SIGHUP handler");
+
+    while hangup.recv().await.is_some() {
+        match state.reload() {
+            Ok(()) => tracing::info!("reloaded {}", state.path().display()),
+            Err(err) => tracing::error!("reload failed, keeping old config: {:#}", err),
+        }
+
+        if let Err(err) = certs.reload_if_changed(&state.config()) {
+            tracing::error!("reloading certificate failed, keeping old one: {:#}", err);
+        }
+    }
 }
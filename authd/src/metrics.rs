@@ -0,0 +1,163 @@
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, register_int_gauge_vec, Encoder, HistogramTimer, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+
+use crate::state::Database;
+
+lazy_static! {
+    pub static ref REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "authd_requests_total",
+        "RPC requests handled, by method",
+        &["method"]
+    )
+    .unwrap();
+    pub static ref NOT_FOUND: IntCounterVec = register_int_counter_vec!(
+        "authd_not_found_total",
+        "Lookups that matched no user or group, by method",
+        &["method"]
+    )
+    .unwrap();
+    pub static ref LATENCY: HistogramVec = register_histogram_vec!(
+        "authd_request_duration_seconds",
+        "Time spent handling an RPC request, by method",
+        &["method"],
+        exponential_buckets(0.00001, 4.0, 10).unwrap()
+    )
+    .unwrap();
+    pub static ref TLS_CONNECTIONS: IntGauge =
+        register_int_gauge!("authd_tls_connections", "Currently open TLS connections").unwrap();
+    pub static ref HANDSHAKE_FAILURES: IntCounter = register_int_counter!(
+        "authd_tls_handshake_failures_total",
+        "TLS handshakes that failed"
+    )
+    .unwrap();
+    pub static ref CONFIG_RELOADS: IntCounter = register_int_counter!(
+        "authd_config_reloads_total",
+        "Successful config reloads since startup"
+    )
+    .unwrap();
+    pub static ref CONFIG_RELOAD_FAILURES: IntCounter = register_int_counter!(
+        "authd_config_reload_failures_total",
+        "Config reloads rejected because the new config was invalid"
+    )
+    .unwrap();
+    pub static ref CONFIG_LAST_SUCCESS: IntGauge = register_int_gauge!(
+        "authd_config_last_success_timestamp_seconds",
+        "Unix time the config was last loaded successfully"
+    )
+    .unwrap();
+    pub static ref DATABASE_SIZE: IntGaugeVec = register_int_gauge_vec!(
+        "authd_database_entries",
+        "Entries currently being served, by kind",
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref TLS_CERT_EXPIRY: IntGauge = register_int_gauge!(
+        "authd_tls_cert_expiry_timestamp_seconds",
+        "Unix time the certificate being served expires"
+    )
+    .unwrap();
+    pub static ref REPLICATION_CONNECTED: IntGauge = register_int_gauge!(
+        "authd_replication_connected",
+        "1 while a replica is following its primary"
+    )
+    .unwrap();
+    pub static ref REPLICATION_LAST_SYNC: IntGauge = register_int_gauge!(
+        "authd_replication_last_sync_timestamp_seconds",
+        "Unix time a replica last heard from its primary"
+    )
+    .unwrap();
+}
+
+/// Counts a request to `method` and times it until the returned timer is dropped
+pub fn track(method: &'static str) -> HistogramTimer {
+    REQUESTS.with_label_values(&[method]).inc();
+    LATENCY.with_label_values(&[method]).start_timer()
+}
+
+/// Passes through the result of a lookup, counting it if nothing was found
+pub fn lookup<T>(method: &'static str, found: Option<T>) -> Option<T> {
+    if found.is_none() {
+        NOT_FOUND.with_label_values(&[method]).inc();
+    }
+    found
+}
+
+fn unix_time() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default() as i64
+}
+
+/// Records a successful config load
+pub fn record_load() {
+    CONFIG_LAST_SUCCESS.set(unix_time());
+}
+
+/// Updates the gauges describing the database being served
+pub fn record_database(database: &Database) {
+    DATABASE_SIZE
+        .with_label_values(&["users"])
+        .set(database.users.len() as i64);
+    DATABASE_SIZE
+        .with_label_values(&["groups"])
+        .set(database.groups.len() as i64);
+}
+
+/// Records whether a replica is following its primary
+pub fn record_replication(connected: bool) {
+    REPLICATION_CONNECTED.set(connected as i64);
+    if connected {
+        REPLICATION_LAST_SYNC.set(unix_time());
+    }
+}
+
+/// Serves the Prometheus text format on `/metrics`
+pub async fn serve(addr: SocketAddr) -> anyhow::Result<()> {
+    let make_svc =
+        make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle_request)) });
+
+    tracing::info!("serving metrics on {:?}", addr);
+    Server::try_bind(&addr)?.serve(make_svc).await?;
+
+    Ok(())
+}
+
+async fn handle_request(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if req.uri().path() != "/metrics" {
+        let mut not_found = Response::new(Body::empty());
+        *not_found.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(not_found);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(err) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        tracing::warn!("encoding metrics failed: {}", err);
+        let mut error = Response::new(Body::empty());
+        *error.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        return Ok(error);
+    }
+
+    let mut response = Response::new(Body::from(buffer));
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        encoder.format_type().parse().expect("valid content type"),
+    );
+    Ok(response)
+}
@@ -0,0 +1,105 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::bail;
+use libcosiauthd::{
+    protocol::capability, AuthdClient, Changes, Failover, FailoverOrder, Replica, Trust,
+};
+use tarpc::context;
+use tracing::{info, warn};
+
+use crate::{config::ReplicateConfig, state::State};
+
+/// How long each `changes_since` long-poll is left open
+const LONG_POLL: Duration = Duration::from_secs(60);
+
+/// Delay before reconnecting after the primary couldn't be reached
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Keeps a replica's database in step with its primary. Never returns.
+///
+/// The primary's address and certificate are re-read from the config on every reconnect, so
+/// pointing a replica at a new primary only needs a reload.
+pub async fn follow(state: Arc<State>) {
+    let mut replica = Replica::default();
+
+    loop {
+        let Some(settings) = state.config().replicate.clone() else {
+            return;
+        };
+
+        match connect(&settings).await {
+            Ok(client) => {
+                info!("following {}", settings.primary);
+                if let Err(err) = follow_client(&state, &client, &mut replica).await {
+                    warn!("lost primary {}: {}", settings.primary, err);
+                }
+            }
+            Err(err) => warn!(
+                "connecting to primary {} failed: {:#}",
+                settings.primary, err
+            ),
+        }
+
+        state.disconnected();
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+}
+
+async fn connect(settings: &ReplicateConfig) -> anyhow::Result<AuthdClient> {
+    let trust = Trust::load(&settings.cert, settings.pins.clone())?;
+    let failover = Failover::new(settings.primary.clone().into(), FailoverOrder::Ordered)?;
+    let connected = failover
+        .connect(
+            &trust,
+            settings.server_name.as_deref(),
+            settings.wire_format,
+        )
+        .await?;
+    if !connected.server.supports(capability::CHANGES) {
+        bail!(
+            "primary {} ({}) is too old to replicate from",
+            connected.addr,
+            connected.server.software
+        );
+    }
+    Ok(connected.client)
+}
+
+/// Applies changes from the primary until the connection fails
+async fn follow_client(
+    state: &State,
+    client: &AuthdClient,
+    replica: &mut Replica,
+) -> Result<(), tarpc::client::RpcError> {
+    loop {
+        let mut ctx = context::current();
+        ctx.deadline = SystemTime::now() + LONG_POLL;
+
+        let changes = client.changes_since(ctx, replica.generation).await?;
+        match &changes {
+            Changes::Resync { generation, .. } => info!("resynced to generation {}", generation),
+            Changes::Diff(diff) if !diff.is_empty() => info!(
+                "updated to generation {}: {} users and {} groups changed",
+                diff.generation,
+                diff.users.len() + diff.removed_users.len(),
+                diff.groups.len() + diff.removed_groups.len()
+            ),
+            Changes::Diff(_) => {}
+        }
+
+        if !replica.apply(changes) {
+            // Out of step with the primary, so ask for everything next time
+            warn!(
+                "received changes that don't follow on from generation {}, resyncing",
+                replica.generation
+            );
+            replica.generation = 0;
+            continue;
+        }
+
+        state.synced(replica);
+    }
+}
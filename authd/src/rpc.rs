@@ -1,60 +1,326 @@
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
-use libcosiauthd::{Group, User, Authd};
+use libcosiauthd::{
+    batch::find_many, page, protocol, AttributeError, Attributes, Authd, Changes, Cursor, Group,
+    Hello, Page, PageError, ProtocolError, Search, SearchError, ServerHello, Status, User,
+};
+use sha2::{Digest, Sha256};
 use tarpc::context::Context;
 
-use crate::config::Config;
+use crate::{
+    metrics,
+    state::{Database, State},
+};
+
+/// Longest a `changes_since` call is held open waiting for a change
+const MAX_LONG_POLL: Duration = Duration::from_secs(300);
 
 #[derive(Debug, Clone)]
 pub struct AuthdSession {
-    pub config: Arc<Config>,
+    pub state: Arc<State>,
 }
 
 impl AuthdSession {
-    pub fn new(config: Arc<Config>) -> Self {
-        Self { config }
+    pub fn new(state: Arc<State>) -> Self {
+        Self { state }
+    }
+
+    /// Checks that `token` is one of the admin tokens in the config, logging `method` if it isn't
+    fn is_admin(&self, token: &str, method: &str) -> bool {
+        let hash: String = Sha256::digest(token.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        let config = self.state.config();
+        let allowed = config
+            .admin_tokens
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(&hash));
+        if !allowed {
+            tracing::warn!("refusing {} without a valid admin token", method);
+        }
+        allowed
+    }
+
+    /// The generation of the database a listing is paging through
+    fn database_for(&self, cursor: Option<Cursor>) -> Result<Arc<Database>, PageError> {
+        match cursor {
+            None => Ok(self.state.database()),
+            Some(cursor) => self
+                .state
+                .database_at(cursor.generation)
+                .ok_or(PageError::Expired),
+        }
     }
 }
 
 #[tarpc::server]
 impl Authd for AuthdSession {
+    async fn hello(self, _ctx: Context, client: Hello) -> Result<ServerHello, ProtocolError> {
+        let _timer = metrics::track("hello");
+
+        if let Err(err) = protocol::check_client(&client) {
+            tracing::warn!("refusing {}: {}", client.software, err);
+            return Err(err);
+        }
+
+        Ok(ServerHello::new(concat!(
+            "authd/",
+            env!("CARGO_PKG_VERSION")
+        )))
+    }
+
     async fn get_all_groups(self, _ctx: Context) -> Vec<Group> {
-        self.config.groups.clone()
+        let _timer = metrics::track("get_all_groups");
+
+        self.state.database().groups.clone()
     }
 
     async fn get_group_by_name(self, _ctx: Context, name: String) -> Option<Group> {
-        self.config
+        let _timer = metrics::track("get_group_by_name");
+
+        let group = self
+            .state
+            .database()
             .groups
             .iter()
             .find(|group| group.name == name)
-            .map(Group::clone)
+            .map(Group::clone);
+        metrics::lookup("get_group_by_name", group)
     }
 
     async fn get_group_by_gid(self, _ctx: Context, gid: u32) -> Option<Group> {
-        self.config
+        let _timer = metrics::track("get_group_by_gid");
+
+        let group = self
+            .state
+            .database()
             .groups
             .iter()
             .find(|group| group.gid == gid)
-            .map(Group::clone)
+            .map(Group::clone);
+        metrics::lookup("get_group_by_gid", group)
     }
 
     async fn get_all_passwd(self, _ctx: Context) -> Vec<User> {
-        self.config.users.clone()
+        let _timer = metrics::track("get_all_passwd");
+
+        self.state.database().users.clone()
     }
 
     async fn get_passwd_by_name(self, _ctx: Context, name: String) -> Option<User> {
-        self.config
+        let _timer = metrics::track("get_passwd_by_name");
+
+        let user = self
+            .state
+            .database()
             .users
             .iter()
             .find(|user| user.name == name)
-            .map(User::clone)
+            .map(User::clone);
+        metrics::lookup("get_passwd_by_name", user)
     }
 
     async fn get_passwd_by_uid(self, _ctx: Context, uid: u32) -> Option<User> {
-        self.config
+        let _timer = metrics::track("get_passwd_by_uid");
+
+        let user = self
+            .state
+            .database()
             .users
             .iter()
             .find(|user| user.id == uid)
-            .map(User::clone)
+            .map(User::clone);
+        metrics::lookup("get_passwd_by_uid", user)
+    }
+
+    async fn get_groups_by_name(self, _ctx: Context, names: Vec<String>) -> Vec<Option<Group>> {
+        let _timer = metrics::track("get_groups_by_name");
+
+        let database = self.state.database();
+        let max = self.state.config().max_batch_size;
+        find_many(
+            &database.groups,
+            |group| group.name.as_str(),
+            names.iter().map(String::as_str),
+            max,
+        )
+        .into_iter()
+        .map(|group| metrics::lookup("get_groups_by_name", group))
+        .collect()
+    }
+
+    async fn get_groups_by_gid(self, _ctx: Context, gids: Vec<u32>) -> Vec<Option<Group>> {
+        let _timer = metrics::track("get_groups_by_gid");
+
+        let database = self.state.database();
+        let max = self.state.config().max_batch_size;
+        find_many(&database.groups, |group| group.gid, gids, max)
+            .into_iter()
+            .map(|group| metrics::lookup("get_groups_by_gid", group))
+            .collect()
+    }
+
+    async fn get_passwds_by_name(self, _ctx: Context, names: Vec<String>) -> Vec<Option<User>> {
+        let _timer = metrics::track("get_passwds_by_name");
+
+        let database = self.state.database();
+        let max = self.state.config().max_batch_size;
+        find_many(
+            &database.users,
+            |user| user.name.as_str(),
+            names.iter().map(String::as_str),
+            max,
+        )
+        .into_iter()
+        .map(|user| metrics::lookup("get_passwds_by_name", user))
+        .collect()
+    }
+
+    async fn get_passwds_by_uid(self, _ctx: Context, uids: Vec<u32>) -> Vec<Option<User>> {
+        let _timer = metrics::track("get_passwds_by_uid");
+
+        let database = self.state.database();
+        let max = self.state.config().max_batch_size;
+        find_many(&database.users, |user| user.id, uids, max)
+            .into_iter()
+            .map(|user| metrics::lookup("get_passwds_by_uid", user))
+            .collect()
+    }
+
+    async fn list_groups(
+        self,
+        _ctx: Context,
+        cursor: Option<Cursor>,
+        limit: u32,
+    ) -> Result<Page<Group>, PageError> {
+        let _timer = metrics::track("list_groups");
+
+        let database = self.database_for(cursor)?;
+        let max = self.state.config().max_page_size;
+        Ok(page::page(
+            &database.groups,
+            database.generation,
+            cursor,
+            limit,
+            max,
+        ))
+    }
+
+    async fn list_passwd(
+        self,
+        _ctx: Context,
+        cursor: Option<Cursor>,
+        limit: u32,
+    ) -> Result<Page<User>, PageError> {
+        let _timer = metrics::track("list_passwd");
+
+        let database = self.database_for(cursor)?;
+        let max = self.state.config().max_page_size;
+        Ok(page::page(
+            &database.users,
+            database.generation,
+            cursor,
+            limit,
+            max,
+        ))
+    }
+
+    async fn search_users(
+        self,
+        _ctx: Context,
+        token: String,
+        query: Search,
+    ) -> Result<Vec<User>, SearchError> {
+        let _timer = metrics::track("search_users");
+        if !self.is_admin(&token, "search_users") {
+            return Err(SearchError::Unauthorized);
+        }
+
+        let database = self.state.database();
+        let max = self.state.config().max_search_results;
+        query.users(&database.users, &database.groups, max)
+    }
+
+    async fn search_groups(
+        self,
+        _ctx: Context,
+        token: String,
+        query: Search,
+    ) -> Result<Vec<Group>, SearchError> {
+        let _timer = metrics::track("search_groups");
+        if !self.is_admin(&token, "search_groups") {
+            return Err(SearchError::Unauthorized);
+        }
+
+        let database = self.state.database();
+        let max = self.state.config().max_search_results;
+        query.groups(&database.groups, max)
+    }
+
+    async fn get_user_attributes(
+        self,
+        _ctx: Context,
+        token: String,
+        name: String,
+    ) -> Result<Option<Attributes>, AttributeError> {
+        let _timer = metrics::track("get_user_attributes");
+        // Attributes aren't replicated, so a replica can't tell whether a user has none
+        if self.state.is_replica() {
+            return Err(AttributeError::Unavailable);
+        }
+        if !self.is_admin(&token, "get_user_attributes") {
+            return Err(AttributeError::Unauthorized);
+        }
+
+        Ok(self.state.config().user_attributes(&name).cloned())
+    }
+
+    async fn get_group_attributes(
+        self,
+        _ctx: Context,
+        token: String,
+        name: String,
+    ) -> Result<Option<Attributes>, AttributeError> {
+        let _timer = metrics::track("get_group_attributes");
+        if self.state.is_replica() {
+            return Err(AttributeError::Unavailable);
+        }
+        if !self.is_admin(&token, "get_group_attributes") {
+            return Err(AttributeError::Unauthorized);
+        }
+
+        Ok(self.state.config().group_attributes(&name).cloned())
+    }
+
+    async fn status(self, _ctx: Context) -> Status {
+        let _timer = metrics::track("status");
+
+        self.state.status()
+    }
+
+    async fn changes_since(self, ctx: Context, generation: u64) -> Changes {
+        // Not timed, since long-polls would swamp the latency histogram
+        metrics::REQUESTS
+            .with_label_values(&["changes_since"])
+            .inc();
+
+        let mut updates = self.state.subscribe();
+        if *updates.borrow() == generation {
+            // Answer a little before the deadline so the empty diff still reaches the client
+            let wait = ctx
+                .deadline
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+                .saturating_sub(Duration::from_secs(1))
+                .min(MAX_LONG_POLL);
+            let _ = tokio::time::timeout(wait, updates.changed()).await;
+        }
+
+        self.state.changes_since(generation)
     }
 }
@@ -0,0 +1,492 @@
+//! Rules deciding who is in a dynamic group.
+//!
+//! A rule tests a user's [attributes](libcosiauthd::attributes), plus `name`, `uid` and `gecos`:
+//!
+//! ```text
+//! class_year = 2027
+//! not disabled
+//! (role = "mentor" or role = "staff") and uid >= 1000
+//! ```
+//!
+//! Comparisons are `=`, `!=`, `<`, `<=`, `>` and `>=` against a number, a quoted string, `true` or
+//! `false`, combined with `and`, `or`, `not` and parentheses. An attribute on its own is true if it
+//! is `true`. A comparison with an attribute the user doesn't have, or one of another type, is
+//! false, so `not disabled` also matches users with no `disabled` attribute.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, bail};
+use libcosiauthd::{Attributes, User, Value};
+use serde::{Deserialize, Serialize};
+
+/// A parsed rule, kept together with its source so configs can be written back out
+#[derive(Debug, Clone)]
+pub struct Rule {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(String, Op, Value),
+    /// An attribute on its own
+    Is(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Rule {
+    /// Whether the user with `attributes` matches
+    pub fn matches(&self, user: &User, attributes: &Attributes) -> bool {
+        self.expr.eval(&|name| match name {
+            "name" => Some(Value::String(user.name.clone())),
+            "uid" => Some(Value::Int(user.id as i64)),
+            "gecos" => user.gecos.clone().map(Value::String),
+            _ => attributes.get(name),
+        })
+    }
+}
+
+impl Expr {
+    fn eval(&self, lookup: &dyn Fn(&str) -> Option<Value>) -> bool {
+        match self {
+            Expr::Or(a, b) => a.eval(lookup) || b.eval(lookup),
+            Expr::And(a, b) => a.eval(lookup) && b.eval(lookup),
+            Expr::Not(a) => !a.eval(lookup),
+            Expr::Is(name) => lookup(name) == Some(Value::Bool(true)),
+            Expr::Compare(name, op, expected) => {
+                let Some(actual) = lookup(name) else {
+                    return false;
+                };
+                let ordering = match (&actual, expected) {
+                    (Value::Int(a), Value::Int(b)) => a.cmp(b),
+                    (Value::String(a), Value::String(b)) => a.cmp(b),
+                    (Value::Bool(a), Value::Bool(b)) if matches!(op, Op::Eq | Op::Ne) => a.cmp(b),
+                    _ => return false,
+                };
+                match op {
+                    Op::Eq => ordering.is_eq(),
+                    Op::Ne => ordering.is_ne(),
+                    Op::Lt => ordering.is_lt(),
+                    Op::Le => ordering.is_le(),
+                    Op::Gt => ordering.is_gt(),
+                    Op::Ge => ordering.is_ge(),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Literal(Value),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+fn tokenize(source: &str) -> anyhow::Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' => {
+                chars.next();
+                tokens.push(if c == '(' { Token::Open } else { Token::Close });
+            }
+            '=' | '!' | '<' | '>' => {
+                chars.next();
+                let equals = chars.next_if_eq(&'=').is_some();
+                tokens.push(Token::Op(match (c, equals) {
+                    ('=', _) => Op::Eq,
+                    ('!', true) => Op::Ne,
+                    ('<', false) => Op::Lt,
+                    ('<', true) => Op::Le,
+                    ('>', false) => Op::Gt,
+                    ('>', true) => Op::Ge,
+                    _ => bail!("expected != after !"),
+                }));
+            }
+            '"' => {
+                chars.next();
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            string.push(chars.next().ok_or_else(|| anyhow!("unterminated string"))?)
+                        }
+                        Some(c) => string.push(c),
+                        None => bail!("unterminated string"),
+                    }
+                }
+                tokens.push(Token::Literal(Value::String(string)));
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut number = String::from(c);
+                chars.next();
+                while let Some(c) = chars.next_if(char::is_ascii_digit) {
+                    number.push(c);
+                }
+                let number = number
+                    .parse()
+                    .map_err(|_| anyhow!("{} is not a number", number))?;
+                tokens.push(Token::Literal(Value::Int(number)));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut word = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_alphanumeric() || *c == '_') {
+                    word.push(c);
+                }
+                tokens.push(match word.as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    "true" => Token::Literal(Value::Bool(true)),
+                    "false" => Token::Literal(Value::Bool(false)),
+                    _ => Token::Ident(word),
+                });
+            }
+            c => bail!("unexpected {:?}", c),
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Deepest `not`s and parentheses may nest, so a rule can't overflow the stack parsing it
+const MAX_DEPTH: usize = 64;
+
+/// Recursive descent over the tokens, with `or` binding loosest and `not` tightest
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// `not`s and parentheses currently open
+    depth: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.tokens.get(self.pos) == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Parses with `f` one level further in
+    fn nested(&mut self, f: fn(&mut Self) -> anyhow::Result<Expr>) -> anyhow::Result<Expr> {
+        if self.depth >= MAX_DEPTH {
+            bail!("rule nests more than {} deep", MAX_DEPTH);
+        }
+        self.depth += 1;
+        let expr = f(self);
+        self.depth -= 1;
+        expr
+    }
+
+    fn or(&mut self) -> anyhow::Result<Expr> {
+        let mut expr = self.and()?;
+        while self.eat(&Token::Or) {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> anyhow::Result<Expr> {
+        let mut expr = self.not()?;
+        while self.eat(&Token::And) {
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> anyhow::Result<Expr> {
+        if self.eat(&Token::Not) {
+            return Ok(Expr::Not(Box::new(self.nested(Self::not)?)));
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> anyhow::Result<Expr> {
+        match self.next() {
+            Some(Token::Open) => {
+                let expr = self.nested(Self::or)?;
+                if !self.eat(&Token::Close) {
+                    bail!("missing )");
+                }
+                Ok(expr)
+            }
+            Some(Token::Ident(name)) => match self.tokens.get(self.pos) {
+                Some(Token::Op(op)) => {
+                    let op = *op;
+                    self.pos += 1;
+                    match self.next() {
+                        Some(Token::Literal(value)) => Ok(Expr::Compare(name, op, value)),
+                        _ => bail!("expected a value to compare {} with", name),
+                    }
+                }
+                _ => Ok(Expr::Is(name)),
+            },
+            Some(token) => bail!("unexpected {:?}", token),
+            None => bail!("rule ends early"),
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+            depth: 0,
+        };
+        let expr = parser.or()?;
+        if parser.pos < parser.tokens.len() {
+            bail!("unexpected {:?}", parser.tokens[parser.pos]);
+        }
+
+        Ok(Self {
+            source: s.to_string(),
+            expr,
+        })
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Rule {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+        let s = String::deserialize(deserializer)?;
+        Rule::from_str(&s).map_err(|e| D::Error::custom(format!("rule {:?}: {}", s, e)))
+    }
+}
+
+impl Serialize for Rule {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Expr {
+        Rule::from_str(source).unwrap().expr
+    }
+
+    fn error(source: &str) -> String {
+        Rule::from_str(source).unwrap_err().to_string()
+    }
+
+    fn is(name: &str) -> Box<Expr> {
+        Box::new(Expr::Is(name.to_string()))
+    }
+
+    fn user(name: &str, id: u32) -> User {
+        User {
+            name: name.to_string(),
+            id,
+            gecos: None,
+            shells: Vec::new(),
+        }
+    }
+
+    fn attributes(class_year: Option<i64>, custom: &[(&str, Value)]) -> Attributes {
+        Attributes {
+            class_year,
+            custom: custom
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.clone()))
+                .collect(),
+            ..Attributes::default()
+        }
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and_tighter_than_or() {
+        assert_eq!(
+            parse("a or b and not c"),
+            Expr::Or(
+                is("a"),
+                Box::new(Expr::And(is("b"), Box::new(Expr::Not(is("c")))))
+            )
+        );
+        assert_eq!(
+            parse("not a and b"),
+            Expr::And(Box::new(Expr::Not(is("a"))), is("b"))
+        );
+        assert_eq!(
+            parse("(a or b) and c"),
+            Expr::And(Box::new(Expr::Or(is("a"), is("b"))), is("c"))
+        );
+        assert_eq!(parse("not not a"), Expr::Not(Box::new(Expr::Not(is("a")))));
+    }
+
+    #[test]
+    fn comparisons() {
+        assert_eq!(
+            parse("role != \"staff\""),
+            Expr::Compare(
+                "role".to_string(),
+                Op::Ne,
+                Value::String("staff".to_string())
+            )
+        );
+        assert_eq!(
+            parse("uid>=1000"),
+            Expr::Compare("uid".to_string(), Op::Ge, Value::Int(1000))
+        );
+        assert_eq!(
+            parse("disabled = false"),
+            Expr::Compare("disabled".to_string(), Op::Eq, Value::Bool(false))
+        );
+    }
+
+    #[test]
+    fn bang_needs_equals() {
+        assert_eq!(error("role ! \"staff\""), "expected != after !");
+        assert_eq!(error("!disabled"), "expected != after !");
+    }
+
+    #[test]
+    fn negative_numbers() {
+        assert_eq!(
+            parse("balance > -5"),
+            Expr::Compare("balance".to_string(), Op::Gt, Value::Int(-5))
+        );
+        assert_eq!(
+            parse("balance=-12"),
+            Expr::Compare("balance".to_string(), Op::Eq, Value::Int(-12))
+        );
+        assert_eq!(error("balance > -"), "- is not a number");
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(
+            parse(r#"motto = "say \"hi\"""#),
+            Expr::Compare(
+                "motto".to_string(),
+                Op::Eq,
+                Value::String("say \"hi\"".to_string())
+            )
+        );
+        assert_eq!(error(r#"role = "mentor"#), "unterminated string");
+        assert_eq!(error(r#"role = "mentor\"#), "unterminated string");
+    }
+
+    #[test]
+    fn rejects_trailing_and_missing_tokens() {
+        assert_eq!(error("a b"), "unexpected Ident(\"b\")");
+        assert_eq!(error("a)"), "unexpected Close");
+        assert_eq!(error("(a"), "missing )");
+        assert_eq!(error("a and"), "rule ends early");
+        assert_eq!(error(""), "rule ends early");
+        assert_eq!(error("uid >="), "expected a value to compare uid with");
+    }
+
+    #[test]
+    fn nesting_is_bounded() {
+        let nested = |depth| format!("{}a{}", "(".repeat(depth), ")".repeat(depth));
+        parse(&nested(MAX_DEPTH));
+        parse(&format!("{}a", "not ".repeat(MAX_DEPTH)));
+
+        let too_deep = format!("rule nests more than {} deep", MAX_DEPTH);
+        assert_eq!(error(&nested(MAX_DEPTH + 1)), too_deep);
+        assert_eq!(error(&format!("{}a", "not ".repeat(100_000))), too_deep);
+    }
+
+    #[test]
+    fn mismatched_types_never_match() {
+        let alice = user("alice", 1001);
+        let attrs = attributes(Some(2027), &[("disabled", Value::Bool(true))]);
+        let matches = |source: &str| Rule::from_str(source).unwrap().matches(&alice, &attrs);
+
+        assert!(matches("class_year = 2027"));
+        assert!(!matches("class_year = \"2027\""));
+        assert!(!matches("class_year != \"2027\""));
+        assert!(matches("not class_year = \"2027\""));
+        assert!(!matches("disabled < true"));
+        assert!(!matches("disabled = 1"));
+        assert!(!matches("name > 5"));
+        assert!(!matches("class_year"));
+        assert!(!matches("missing = 1"));
+        assert!(!matches("missing != 1"));
+    }
+
+    #[test]
+    fn module_doc_examples() {
+        let class_year = Rule::from_str("class_year = 2027").unwrap();
+        let not_disabled = Rule::from_str("not disabled").unwrap();
+        let staff =
+            Rule::from_str(r#"(role = "mentor" or role = "staff") and uid >= 1000"#).unwrap();
+
+        let mentor = attributes(Some(2027), &[("role", Value::String("mentor".to_string()))]);
+        let disabled = attributes(Some(2026), &[("disabled", Value::Bool(true))]);
+        let enabled = attributes(None, &[("disabled", Value::Bool(false))]);
+        let none = Attributes::default();
+
+        assert!(class_year.matches(&user("alice", 1001), &mentor));
+        assert!(!class_year.matches(&user("bob", 1002), &disabled));
+        assert!(!class_year.matches(&user("carol", 1003), &none));
+
+        assert!(not_disabled.matches(&user("alice", 1001), &mentor));
+        assert!(!not_disabled.matches(&user("bob", 1002), &disabled));
+        assert!(not_disabled.matches(&user("dave", 1004), &enabled));
+        assert!(not_disabled.matches(&user("carol", 1003), &none));
+
+        assert!(staff.matches(&user("alice", 1001), &mentor));
+        assert!(!staff.matches(&user("root", 0), &mentor));
+        assert!(!staff.matches(&user("carol", 1003), &none));
+        let role = |role: &str| attributes(None, &[("role", Value::String(role.to_string()))]);
+        assert!(staff.matches(&user("erin", 1005), &role("staff")));
+        assert!(!staff.matches(&user("frank", 1006), &role("student")));
+
+        // Rules keep their source
+        assert_eq!(
+            staff.to_string(),
+            r#"(role = "mentor" or role = "staff") and uid >= 1000"#
+        );
+    }
+}
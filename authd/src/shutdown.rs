@@ -0,0 +1,89 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{Sink, Stream};
+use pin_project::pin_project;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+
+/// Resolves once authd receives SIGTERM or SIGINT
+pub async fn wait_for_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("installing SIGTERM handler");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("installing SIGINT handler");
+
+    tokio::select! {
+        _ = terminate.recv() => tracing::info!("received SIGTERM"),
+        _ = interrupt.recv() => tracing::info!("received SIGINT"),
+    }
+}
+
+/// Wraps a transport so its read half ends once shutdown begins.
+///
+/// tarpc treats the end of the read half like a client hanging up: in-flight requests still run to
+/// completion and their responses are flushed before the channel closes, but no new requests are
+/// read.
+#[pin_project]
+pub struct Draining<T> {
+    #[pin]
+    inner: T,
+    shutdown: Pin<Box<dyn Future<Output = ()> + Send>>,
+    draining: bool,
+}
+
+impl<T> Draining<T> {
+    pub fn new(inner: T, mut shutdown: watch::Receiver<bool>) -> Self {
+        Self {
+            inner,
+            shutdown: Box::pin(async move {
+                while !*shutdown.borrow() {
+                    if shutdown.changed().await.is_err() {
+                        break;
+                    }
+                }
+            }),
+            draining: false,
+        }
+    }
+}
+
+impl<T: Stream> Stream for Draining<T> {
+    type Item = T::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+
+        if !*this.draining && this.shutdown.as_mut().poll(cx).is_ready() {
+            *this.draining = true;
+        }
+        if *this.draining {
+            return Poll::Ready(None);
+        }
+
+        this.inner.poll_next(cx)
+    }
+}
+
+impl<T: Sink<I>, I> Sink<I> for Draining<T> {
+    type Error = T::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: I) -> Result<(), Self::Error> {
+        self.project().inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_close(cx)
+    }
+}
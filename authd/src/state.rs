@@ -0,0 +1,270 @@
+use std::{
+    collections::VecDeque,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use libcosiauthd::{
+    export::Dump, Changes, DatabaseStatus, Diff, Group, Replica, ReplicationStatus, Status, User,
+};
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+
+use crate::{config::Config, groups, metrics};
+
+/// How many past generations are kept around to answer `changes_since` with a diff
+const HISTORY: usize = 8;
+
+/// One generation of the users and groups being served
+#[derive(Debug, Default)]
+pub struct Database {
+    pub generation: u64,
+    pub users: Vec<User>,
+    pub groups: Vec<Group>,
+}
+
+/// A config file together with the bookkeeping needed to tell loads apart
+#[derive(Debug)]
+struct Loaded {
+    config: Arc<Config>,
+    hash: String,
+    loaded_at: u64,
+}
+
+/// How a replica is getting on with its primary
+#[derive(Debug, Default)]
+struct Replication {
+    connected: bool,
+    synced_at: Option<Instant>,
+}
+
+/// The configuration and database currently being served, shared between every session.
+///
+/// Sessions take a cheap snapshot of the database for each request, so a reload never changes the
+/// data underneath a lookup that is already running.
+///
+/// A primary builds its database from the users and groups in its config. A replica's comes from
+/// its primary instead (see `replica.rs`) and only the rest of its config is reloaded.
+#[derive(Debug)]
+pub struct State {
+    path: PathBuf,
+    started: Instant,
+    loaded: RwLock<Loaded>,
+    /// Recently served generations, oldest first. The last one is being served now.
+    databases: RwLock<VecDeque<Arc<Database>>>,
+    generation: watch::Sender<u64>,
+    /// Only used when this authd is a replica
+    replication: Mutex<Replication>,
+}
+
+impl State {
+    /// Loads the initial config from `path`
+    pub fn load(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let loaded = read_config(&path)?;
+
+        let database = match loaded.config.replicate {
+            // Start counting from the current time so generations keep increasing across restarts
+            // and a replica never mistakes a generation from before a restart for a current one.
+            None => Database {
+                generation: unix_time() * 1000,
+                users: loaded.config.all_users(),
+                groups: groups::expand(&loaded.config.all_groups()),
+            },
+            // Empty until the first sync with the primary
+            Some(_) => Database::default(),
+        };
+
+        metrics::record_load();
+        metrics::record_database(&database);
+
+        Ok(Self {
+            path,
+            started: Instant::now(),
+            loaded: RwLock::new(loaded),
+            generation: watch::channel(database.generation).0,
+            databases: RwLock::new(VecDeque::from([Arc::new(database)])),
+            replication: Mutex::new(Replication::default()),
+        })
+    }
+
+    /// Where the config is read from
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the config currently in effect
+    pub fn config(&self) -> Arc<Config> {
+        self.loaded.read().unwrap().config.clone()
+    }
+
+    /// Returns the database currently being served
+    pub fn database(&self) -> Arc<Database> {
+        self.databases.read().unwrap().back().unwrap().clone()
+    }
+
+    /// Returns a recent generation of the database, if it is still kept
+    pub fn database_at(&self, generation: u64) -> Option<Arc<Database>> {
+        self.databases
+            .read()
+            .unwrap()
+            .iter()
+            .find(|d| d.generation == generation)
+            .cloned()
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.config().replicate.is_some()
+    }
+
+    /// Re-reads the config from disk, keeping the old config if the new one is invalid
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let loaded = match read_config(&self.path) {
+            Ok(loaded) if loaded.config.replicate.is_some() != self.is_replica() => {
+                metrics::CONFIG_RELOAD_FAILURES.inc();
+                bail!("switching between primary and replica needs a restart");
+            }
+            Ok(loaded) => loaded,
+            Err(err) => {
+                metrics::CONFIG_RELOAD_FAILURES.inc();
+                return Err(err);
+            }
+        };
+
+        metrics::CONFIG_RELOADS.inc();
+        metrics::record_load();
+
+        let config = loaded.config.clone();
+        *self.loaded.write().unwrap() = loaded;
+
+        if config.replicate.is_none() {
+            self.publish(Database {
+                generation: self.database().generation + 1,
+                users: config.all_users(),
+                groups: groups::expand(&config.all_groups()),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Starts serving `database`
+    fn publish(&self, database: Database) {
+        let generation = database.generation;
+        metrics::record_database(&database);
+
+        let mut databases = self.databases.write().unwrap();
+        databases.push_back(Arc::new(database));
+        if databases.len() > HISTORY {
+            databases.pop_front();
+        }
+        drop(databases);
+
+        self.generation.send_replace(generation);
+    }
+
+    /// Records that a replica is up to date with its primary, serving the primary's latest
+    /// generation if it hasn't been already
+    pub fn synced(&self, replica: &Replica) {
+        let mut replication = self.replication.lock().unwrap();
+        replication.connected = true;
+        replication.synced_at = Some(Instant::now());
+        drop(replication);
+        metrics::record_replication(true);
+
+        if replica.generation != self.database().generation {
+            self.publish(Database {
+                generation: replica.generation,
+                users: replica.users.clone(),
+                groups: replica.groups.clone(),
+            });
+        }
+    }
+
+    /// Records that a replica lost its connection to the primary
+    pub fn disconnected(&self) {
+        self.replication.lock().unwrap().connected = false;
+        metrics::record_replication(false);
+    }
+
+    /// Watches the generation being served
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.generation.subscribe()
+    }
+
+    /// Describes how to get from `generation` to what is being served now
+    pub fn changes_since(&self, generation: u64) -> Changes {
+        let databases = self.databases.read().unwrap();
+        let current = databases.back().unwrap();
+
+        match databases.iter().find(|d| d.generation == generation) {
+            Some(old) => Changes::Diff(Diff::between(
+                (old.generation, &old.users[..], &old.groups[..]),
+                (current.generation, &current.users[..], &current.groups[..]),
+            )),
+            None => Changes::Resync {
+                generation: current.generation,
+                dump: Dump {
+                    users: current.users.clone(),
+                    groups: current.groups.clone(),
+                },
+            },
+        }
+    }
+
+    /// Describes this server for the `status` RPC
+    pub fn status(&self) -> Status {
+        let database = self.database();
+        let loaded = self.loaded.read().unwrap();
+
+        let replication = loaded.config.replicate.as_ref().map(|replicate| {
+            let replication = self.replication.lock().unwrap();
+            ReplicationStatus {
+                primary: replicate.primary.to_string(),
+                connected: replication.connected,
+                // The primary answers as soon as anything changes, and otherwise at the end of each
+                // long-poll, so a replica that is following never lags by more than one long-poll
+                lag_secs: replication.synced_at.map(|at| at.elapsed().as_secs()),
+            }
+        });
+
+        Status {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            database: Some(DatabaseStatus {
+                generation: database.generation,
+                config_hash: loaded.hash.clone(),
+                last_reload: loaded.loaded_at,
+                users: database.users.len(),
+                groups: database.groups.len(),
+            }),
+            upstream: None,
+            replication,
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn read_config(path: &Path) -> anyhow::Result<Loaded> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    let config = Config::parse(&contents).with_context(|| format!("loading {}", path.display()))?;
+
+    Ok(Loaded {
+        config: Arc::new(config),
+        hash: Sha256::digest(contents.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+        loaded_at: unix_time(),
+    })
+}
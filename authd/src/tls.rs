@@ -0,0 +1,286 @@
+//! authd's certificate and private key.
+//!
+//! Both files may be PEM or DER. The certificate file may hold a whole chain, leaf first, which is
+//! sent to clients as is. Keys may be PKCS#8, PKCS#1 RSA or SEC1 EC keys.
+//!
+//! The files are checked for changes every minute and on SIGHUP, so renewed certificates are
+//! picked up without a restart. Connections already open keep the certificate they were accepted
+//! with; only new handshakes see the new one. A key that doesn't belong to the certificate is
+//! refused, so replacing one file before the other keeps the old pair until both are in place.
+
+use std::{
+    fs,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Context};
+use libcosiauthd::tls::load_certs;
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{any_supported_type, CertifiedKey, SigningKey},
+    Certificate, PrivateKey, SignatureScheme,
+};
+use rustls_pemfile::Item;
+
+use crate::{config::Config, metrics, state::State};
+
+/// How often the certificate and key files are checked for changes
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Start warning this long before the certificate expires
+const WARN_BEFORE_EXPIRY: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// How often an approaching expiry is logged
+const WARN_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Hands out the current certificate and key to every new TLS handshake
+pub struct CertResolver {
+    current: RwLock<Loaded>,
+}
+
+struct Loaded {
+    key: Arc<CertifiedKey>,
+    cert_path: String,
+    key_path: String,
+    /// Modification times of both files when they were loaded
+    modified: (Option<SystemTime>, Option<SystemTime>),
+    /// Unix time the leaf certificate expires
+    not_after: i64,
+}
+
+impl CertResolver {
+    pub fn load(config: &Config) -> anyhow::Result<Arc<Self>> {
+        let loaded = load(&config.cert, &config.key)?;
+        check_expiry(&loaded);
+
+        Ok(Arc::new(Self {
+            current: RwLock::new(loaded),
+        }))
+    }
+
+    /// Loads the certificate and key again if either file (or its path in the config) changed,
+    /// keeping the old ones if the new ones are unusable
+    pub fn reload_if_changed(&self, config: &Config) -> anyhow::Result<()> {
+        {
+            let current = self.current.read().unwrap();
+            if current.cert_path == config.cert
+                && current.key_path == config.key
+                && current.modified == modified(&config.cert, &config.key)
+            {
+                return Ok(());
+            }
+        }
+
+        let loaded = load(&config.cert, &config.key)?;
+        tracing::info!("loaded new certificate from {}", config.cert);
+        check_expiry(&loaded);
+        *self.current.write().unwrap() = loaded;
+
+        Ok(())
+    }
+
+    /// Picks up changed files and keeps warning about expiry. Never returns.
+    pub async fn watch(self: Arc<Self>, state: Arc<State>) {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        let mut last_warning = tokio::time::Instant::now();
+
+        loop {
+            interval.tick().await;
+
+            if let Err(err) = self.reload_if_changed(&state.config()) {
+                tracing::error!("reloading certificate failed, keeping old one: {:#}", err);
+            }
+
+            if last_warning.elapsed() >= WARN_INTERVAL {
+                check_expiry(&self.current.read().unwrap());
+                last_warning = tokio::time::Instant::now();
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().key.clone())
+    }
+}
+
+fn modified(cert_path: &str, key_path: &str) -> (Option<SystemTime>, Option<SystemTime>) {
+    let mtime = |path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    (mtime(cert_path), mtime(key_path))
+}
+
+fn load(cert_path: &str, key_path: &str) -> anyhow::Result<Loaded> {
+    // Read the times first, so a file replaced while loading is loaded again next time
+    let modified = modified(cert_path, key_path);
+
+    let certs = load_certs(cert_path)?;
+    let (_, leaf) = x509_parser::parse_x509_certificate(&certs[0].0)
+        .map_err(|err| anyhow!("parsing {}: {}", cert_path, err))?;
+    let not_after = leaf.validity().not_after.timestamp();
+
+    let key = load_key(key_path)?;
+    check_key_matches(&certs[0], key.as_ref())
+        .with_context(|| format!("checking {} against {}", key_path, cert_path))?;
+    metrics::TLS_CERT_EXPIRY.set(not_after);
+
+    Ok(Loaded {
+        key: Arc::new(CertifiedKey::new(certs, key)),
+        cert_path: cert_path.to_string(),
+        key_path: key_path.to_string(),
+        modified,
+        not_after,
+    })
+}
+
+/// Signs a test message with `key` and verifies it with the leaf certificate, so a key that
+/// doesn't belong to the certificate is refused instead of failing every handshake
+fn check_key_matches(leaf: &Certificate, key: &dyn SigningKey) -> anyhow::Result<()> {
+    const MESSAGE: &[u8] = b"authd certificate and key check";
+    let schemes: [(SignatureScheme, &webpki::SignatureAlgorithm); 5] = [
+        (SignatureScheme::ED25519, &webpki::ED25519),
+        (
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            &webpki::ECDSA_P256_SHA256,
+        ),
+        (
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            &webpki::ECDSA_P384_SHA384,
+        ),
+        (
+            SignatureScheme::RSA_PSS_SHA256,
+            &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+        ),
+        (
+            SignatureScheme::RSA_PKCS1_SHA256,
+            &webpki::RSA_PKCS1_2048_8192_SHA256,
+        ),
+    ];
+
+    let offered: Vec<SignatureScheme> = schemes.iter().map(|(scheme, _)| *scheme).collect();
+    let signer = key
+        .choose_scheme(&offered)
+        .ok_or_else(|| anyhow!("the key can't sign with any supported algorithm"))?;
+    let (_, algorithm) = schemes
+        .iter()
+        .find(|(scheme, _)| *scheme == signer.scheme())
+        .ok_or_else(|| anyhow!("the key chose an algorithm that wasn't offered"))?;
+    let signature = signer
+        .sign(MESSAGE)
+        .map_err(|err| anyhow!("signing with the key: {}", err))?;
+
+    let cert = webpki::EndEntityCert::try_from(leaf.0.as_slice())
+        .map_err(|err| anyhow!("parsing the certificate: {}", err))?;
+    cert.verify_signature(algorithm, MESSAGE, &signature)
+        .map_err(|_| anyhow!("the key doesn't belong to the certificate"))
+}
+
+fn check_expiry(loaded: &Loaded) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    let left = loaded.not_after - now;
+
+    if left <= 0 {
+        tracing::error!("certificate {} has expired", loaded.cert_path);
+    } else if left < WARN_BEFORE_EXPIRY.as_secs() as i64 {
+        tracing::warn!(
+            "certificate {} expires in {} days",
+            loaded.cert_path,
+            left / (24 * 60 * 60)
+        );
+    }
+}
+
+fn load_key(path: impl AsRef<Path>) -> anyhow::Result<Arc<dyn SigningKey>> {
+    let path = path.as_ref();
+    let contents = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+
+    let der = if contents.starts_with(b"-----BEGIN") {
+        rustls_pemfile::read_all(&mut &contents[..])
+            .with_context(|| format!("parsing {}", path.display()))?
+            .into_iter()
+            .find_map(|item| match item {
+                Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(key),
+                _ => None,
+            })
+            .ok_or_else(|| anyhow!("{} contains no private key", path.display()))?
+    } else {
+        contents
+    };
+
+    if let Ok(key) = any_supported_type(&PrivateKey(der.clone())) {
+        return Ok(key);
+    }
+
+    // rustls only takes EC keys wrapped in PKCS#8, so try wrapping a SEC1 key for each curve
+    for curve in [SECP256R1, SECP384R1] {
+        if let Ok(key) = any_supported_type(&PrivateKey(sec1_to_pkcs8(&der, curve))) {
+            return Ok(key);
+        }
+    }
+
+    bail!(
+        "{} is not a supported PKCS#8, RSA or EC private key",
+        path.display()
+    )
+}
+
+/// DER encoded OIDs for id-ecPublicKey and the curves rustls supports
+const EC_PUBLIC_KEY: &[u8] = &[0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const SECP256R1: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const SECP384R1: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22];
+
+/// Wraps a SEC1 `ECPrivateKey` in a PKCS#8 `PrivateKeyInfo` (RFC 5915 section 3)
+fn sec1_to_pkcs8(sec1: &[u8], curve: &[u8]) -> Vec<u8> {
+    let algorithm = der(0x30, &[EC_PUBLIC_KEY, curve].concat());
+    let version = [0x02, 0x01, 0x00];
+    der(0x30, &[&version[..], &algorithm, &der(0x04, sec1)].concat())
+}
+
+/// Encodes a DER tag-length-value
+fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = contents.len();
+
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+
+    out.extend_from_slice(contents);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(name: &str) -> String {
+        format!("{}/tests/data/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    #[test]
+    fn loads_matching_cert_and_key() {
+        load(&data("localhost.pem"), &data("localhost.key")).unwrap();
+    }
+
+    #[test]
+    fn refuses_a_key_for_another_cert() {
+        let err = load(&data("localhost.pem"), &data("other.key"))
+            .err()
+            .unwrap();
+        assert!(
+            format!("{:#}", err).contains("doesn't belong to the certificate"),
+            "{:#}",
+            err
+        );
+    }
+}
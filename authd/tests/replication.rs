@@ -0,0 +1,140 @@
+//! A primary and a replica on loopback, checking that the replica serves what the primary loaded
+//! and follows it across a reload.
+
+use std::{
+    fs,
+    net::{SocketAddr, TcpListener},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use libcosiauthd::{connect_client, Trust, User, WireFormat};
+use tarpc::context;
+
+/// Longest the replica may take to catch up with the primary
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Kills the servers when the test ends, however it ends
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn start(config: &Path) -> Server {
+    let child = Command::new(env!("CARGO_BIN_EXE_authd"))
+        .arg(config)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    Server(child)
+}
+
+/// An address nothing is listening on
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+fn data(name: &str) -> String {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/data")
+        .join(name)
+        .display()
+        .to_string()
+}
+
+fn primary_config(listen: SocketAddr, users: &[&str]) -> String {
+    let mut config = format!(
+        "cert = {:?}\nkey = {:?}\nlisten_addr = \"{}\"\n",
+        data("localhost.pem"),
+        data("localhost.key"),
+        listen
+    );
+    for (id, name) in (1001..).zip(users) {
+        config += &format!("\n[[users]]\nname = \"{}\"\nid = {}\n", name, id);
+    }
+    config
+}
+
+fn replica_config(listen: SocketAddr, primary: SocketAddr) -> String {
+    format!(
+        "cert = {cert:?}\nkey = {key:?}\nlisten_addr = \"{}\"\n\n[replicate]\nprimary = \"localhost:{}\"\ncert = {cert:?}\n",
+        listen,
+        primary.port(),
+        cert = data("localhost.pem"),
+        key = data("localhost.key"),
+    )
+}
+
+/// Asks the replica for `name` until it answers with the user or the timeout passes
+async fn wait_for_user(addr: SocketAddr, name: &str) -> User {
+    let trust = Trust::load(data("localhost.pem"), vec![]).unwrap();
+    let start = Instant::now();
+
+    loop {
+        // The replica only starts listening after its first sync
+        if let Ok((client, _)) = connect_client(addr, &trust, "localhost", WireFormat::Json).await {
+            if let Ok(Some(user)) = client
+                .get_passwd_by_name(context::current(), name.to_string())
+                .await
+            {
+                return user;
+            }
+        }
+
+        assert!(
+            start.elapsed() < TIMEOUT,
+            "{} never reached the replica",
+            name
+        );
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+}
+
+fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("authd-replication-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[tokio::test]
+async fn replica_follows_primary() {
+    let dir = temp_dir();
+    let primary_addr = free_addr();
+    let replica_addr = free_addr();
+
+    let primary_path = dir.join("primary.toml");
+    let replica_path = dir.join("replica.toml");
+    fs::write(&primary_path, primary_config(primary_addr, &["alice"])).unwrap();
+    fs::write(&replica_path, replica_config(replica_addr, primary_addr)).unwrap();
+
+    let primary = start(&primary_path);
+    let _replica = start(&replica_path);
+
+    let alice = wait_for_user(replica_addr, "alice").await;
+    assert_eq!(alice.id, 1001);
+
+    fs::write(
+        &primary_path,
+        primary_config(primary_addr, &["alice", "bob"]),
+    )
+    .unwrap();
+    let status = Command::new("kill")
+        .args(["-HUP", &primary.0.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let bob = wait_for_user(replica_addr, "bob").await;
+    assert_eq!(bob.id, 1002);
+
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,79 @@
+//! Compares the wire formats on the largest message authd sends: the response to
+//! `get_all_passwd` for a big directory.
+//!
+//! Run with `cargo bench -p libcosiauthd --bench wire_format`.
+
+use std::{
+    pin::Pin,
+    time::{Duration, Instant},
+};
+
+use bytes::BytesMut;
+use libcosiauthd::{wire::Codec, AuthdResponse, Shell, User, WireFormat};
+use tarpc::{
+    tokio_serde::{Deserializer, Serializer},
+    Response,
+};
+
+const USERS: u32 = 20_000;
+const ROUNDS: u32 = 20;
+
+fn main() {
+    let users = (0..USERS)
+        .map(|i| User {
+            name: format!("user{}", i),
+            id: 10_000 + i,
+            gecos: Some(format!("Lab User {}", i)),
+            shells: vec![Shell::Zsh, Shell::Bash],
+        })
+        .collect();
+    let response = Response {
+        request_id: 1,
+        message: Ok(AuthdResponse::GetAllPasswd(users)),
+    };
+
+    println!(
+        "get_all_passwd with {} users, mean of {} rounds",
+        USERS, ROUNDS
+    );
+    println!(
+        "{:<12} {:>10} {:>12} {:>12}",
+        "format", "bytes", "encode", "decode"
+    );
+
+    for format in [
+        WireFormat::Json,
+        WireFormat::Bincode,
+        WireFormat::MessagePack,
+    ] {
+        let mut codec = Codec::new(format);
+
+        let start = Instant::now();
+        let mut encoded = Default::default();
+        for _ in 0..ROUNDS {
+            encoded = Serializer::serialize(Pin::new(&mut codec), &response).unwrap();
+        }
+        let encode = start.elapsed() / ROUNDS;
+
+        let encoded = BytesMut::from(&encoded[..]);
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            let decoded: Response<AuthdResponse> =
+                Deserializer::deserialize(Pin::new(&mut codec), &encoded).unwrap();
+            drop(decoded);
+        }
+        let decode = start.elapsed() / ROUNDS;
+
+        println!(
+            "{:<12} {:>10} {:>12} {:>12}",
+            format.to_string(),
+            encoded.len(),
+            millis(encode),
+            millis(decode)
+        );
+    }
+}
+
+fn millis(d: Duration) -> String {
+    format!("{:.2}ms", d.as_secs_f64() * 1000.0)
+}
@@ -0,0 +1,52 @@
+//! Prints the order a client would try the servers behind an SRV record in, using a given
+//! nameserver. Handy for checking records against a local stub resolver, e.g.
+//!
+//! ```sh
+//! dnsmasq --no-daemon --port 5353 --no-resolv \
+//!     --srv-host=_cosiauthd._tcp.test,auth1.test,8765,0,60 \
+//!     --srv-host=_cosiauthd._tcp.test,auth2.test,8765,0,40 \
+//!     --srv-host=_cosiauthd._tcp.test,auth3.test,8765,10,0 \
+//!     --host-record=auth1.test,127.0.0.1 \
+//!     --host-record=auth2.test,127.0.0.2 \
+//!     --host-record=auth3.test,127.0.0.3
+//! cargo run -p libcosiauthd --example srv_lookup -- 127.0.0.1:5353 _cosiauthd._tcp.test
+//! ```
+//!
+//! auth1 should come first about 60% of the time, and auth3 always last.
+
+use std::{net::SocketAddr, str::FromStr};
+
+use libcosiauthd::{Failover, FailoverOrder, Servers, SocketName};
+use trust_dns_resolver::{
+    config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    TokioAsyncResolver,
+};
+
+const ROUNDS: usize = 10;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let (Some(nameserver), Some(service)) = (args.next(), args.next()) else {
+        anyhow::bail!("usage: srv_lookup <nameserver ip:port> <srv name>");
+    };
+    let nameserver = SocketAddr::from_str(&nameserver)?;
+
+    let config = ResolverConfig::from_parts(
+        None,
+        vec![],
+        NameServerConfigGroup::from_ips_clear(&[nameserver.ip()], nameserver.port(), true),
+    );
+    let resolver = TokioAsyncResolver::tokio(config, ResolverOpts::default())?;
+
+    let servers = Servers::from(SocketName::Srv(service));
+    let failover = Failover::with_resolver(servers, FailoverOrder::Ordered, resolver);
+
+    for _ in 0..ROUNDS {
+        let candidates = failover.candidates().await?;
+        let candidates: Vec<String> = candidates.iter().map(|c| c.addr.to_string()).collect();
+        println!("{}", candidates.join(" "));
+    }
+
+    Ok(())
+}
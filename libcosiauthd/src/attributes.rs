@@ -0,0 +1,154 @@
+//! Extra facts about users and groups, for tools that use authd as their source of truth.
+//!
+//! Attributes are set in authd's config and only sent by `get_user_attributes` and
+//! `get_group_attributes`, to clients presenting an admin token. They are never part of a [`User`]
+//! or [`Group`], so nothing about them reaches NSS, gecos included.
+//!
+//! [`User`]: crate::User
+//! [`Group`]: crate::Group
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// The attributes of a user or group. Anything without a field of its own goes in `custom`.
+///
+/// Fields this version doesn't know are ignored, so attributes added by newer servers don't break
+/// older clients. authd's config is stricter about misspellings.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Attributes {
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub student_id: Option<String>,
+    #[serde(default)]
+    pub class_year: Option<i64>,
+    /// The name to show people, which may differ from the gecos
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub pronouns: Option<String>,
+    /// Why and when the account is due to go away
+    #[serde(default)]
+    pub expiry_note: Option<String>,
+    #[serde(default)]
+    pub custom: BTreeMap<String, Value>,
+}
+
+/// Attributes with a field of their own, in the order they are listed
+const FIELDS: [&str; 6] = [
+    "email",
+    "student_id",
+    "class_year",
+    "display_name",
+    "pronouns",
+    "expiry_note",
+];
+
+impl Attributes {
+    /// The attribute called `name`, whether it has a field of its own or is custom
+    pub fn get(&self, name: &str) -> Option<Value> {
+        let string = |s: &Option<String>| s.clone().map(Value::String);
+
+        match name {
+            "email" => string(&self.email),
+            "student_id" => string(&self.student_id),
+            "class_year" => self.class_year.map(Value::Int),
+            "display_name" => string(&self.display_name),
+            "pronouns" => string(&self.pronouns),
+            "expiry_note" => string(&self.expiry_note),
+            _ => self.custom.get(name).cloned(),
+        }
+    }
+
+    /// Every attribute that is set, those with fields of their own first
+    pub fn all(&self) -> Vec<(&str, Value)> {
+        FIELDS
+            .iter()
+            .filter_map(|&name| Some((name, self.get(name)?)))
+            .chain(
+                self.custom
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value.clone())),
+            )
+            .collect()
+    }
+}
+
+/// The value of a custom attribute.
+///
+/// Configs and JSON write values as they are, like `true`, `2027` or `"mentor"`. Binary wire
+/// formats can't tell what type a value is without being told, so there they are tagged.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(remote = "Self")]
+pub enum Value {
+    Bool(bool),
+    Int(i64),
+    String(String),
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::String(s) => write!(f, "{:?}", s),
+        }
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if !serializer.is_human_readable() {
+            return Value::serialize(self, serializer);
+        }
+
+        match self {
+            Value::Bool(b) => serializer.serialize_bool(*b),
+            Value::Int(i) => serializer.serialize_i64(*i),
+            Value::String(s) => serializer.serialize_str(s),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if !deserializer.is_human_readable() {
+            return Value::deserialize(deserializer);
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Plain {
+            Bool(bool),
+            Int(i64),
+            String(String),
+        }
+
+        Ok(match Plain::deserialize(deserializer)? {
+            Plain::Bool(b) => Value::Bool(b),
+            Plain::Int(i) => Value::Int(i),
+            Plain::String(s) => Value::String(s),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AttributeError {
+    /// The client didn't present an admin token the server accepts
+    Unauthorized,
+    /// The server couldn't answer, e.g. a replica, which doesn't have the attributes, or a proxy
+    /// that can't reach authd
+    Unavailable,
+}
+
+impl std::fmt::Display for AttributeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AttributeError::Unauthorized => write!(f, "reading attributes needs an admin token"),
+            AttributeError::Unavailable => write!(f, "the server couldn't look up attributes"),
+        }
+    }
+}
+
+impl std::error::Error for AttributeError {}
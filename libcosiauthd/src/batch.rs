@@ -0,0 +1,50 @@
+//! Looking many users or groups up in one request.
+//!
+//! The answer to `get_passwds_by_name` and the other batch lookups holds one entry per key, in the
+//! order they were asked for. Servers look up at most their `max_batch_size` keys of a batch and
+//! answer for just those, so an answer shorter than the batch means the rest have to be asked for
+//! again.
+
+use std::{collections::HashMap, hash::Hash};
+
+/// Looks the first `max` of `keys` up in `entries`, indexing them first so a big batch doesn't
+/// scan the whole database for each key. Like the single lookups, the first entry with a key wins.
+pub fn find_many<'a, T, K>(
+    entries: &'a [T],
+    key: impl Fn(&'a T) -> K,
+    keys: impl IntoIterator<Item = K>,
+    max: u32,
+) -> Vec<Option<T>>
+where
+    T: Clone,
+    K: Eq + Hash,
+{
+    let mut index = HashMap::with_capacity(entries.len());
+    for entry in entries {
+        index.entry(key(entry)).or_insert(entry);
+    }
+
+    keys.into_iter()
+        .take(max as usize)
+        .map(|k| index.get(&k).map(|entry| T::clone(entry)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_in_order() {
+        let entries = [(1, "a"), (2, "b"), (2, "shadowed")];
+        let found = find_many(&entries, |e| e.0, [2, 3, 1], 10);
+        assert_eq!(found, [Some((2, "b")), None, Some((1, "a"))]);
+    }
+
+    #[test]
+    fn stops_at_max() {
+        let entries = [(1, "a"), (2, "b")];
+        let found = find_many(&entries, |e| e.0, [1, 2, 3], 2);
+        assert_eq!(found, [Some((1, "a")), Some((2, "b"))]);
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{export::Dump, Group, User};
+
+/// The answer to `changes_since`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Changes {
+    /// What changed since the requested generation. Empty if nothing has changed yet.
+    Diff(Diff),
+    /// The requested generation is unknown to the server (too old, or from before a restart), so
+    /// here is everything instead
+    Resync { generation: u64, dump: Dump },
+}
+
+/// The difference between two generations of the database. Users and groups are identified by
+/// name.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Diff {
+    pub from: u64,
+    pub generation: u64,
+    /// Users that were added or modified
+    pub users: Vec<User>,
+    pub removed_users: Vec<String>,
+    /// Groups that were added or modified
+    pub groups: Vec<Group>,
+    pub removed_groups: Vec<String>,
+}
+
+impl Diff {
+    /// Works out what changed from the `from` generation to the `to` generation
+    pub fn between(from: (u64, &[User], &[Group]), to: (u64, &[User], &[Group])) -> Self {
+        let (users, removed_users) = diff_by_name(from.1, to.1, |u| &u.name);
+        let (groups, removed_groups) = diff_by_name(from.2, to.2, |g| &g.name);
+
+        Self {
+            from: from.0,
+            generation: to.0,
+            users,
+            removed_users,
+            groups,
+            removed_groups,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+            && self.removed_users.is_empty()
+            && self.groups.is_empty()
+            && self.removed_groups.is_empty()
+    }
+}
+
+fn diff_by_name<T: Clone + PartialEq>(
+    old: &[T],
+    new: &[T],
+    name: impl Fn(&T) -> &String,
+) -> (Vec<T>, Vec<String>) {
+    let old_by_name: HashMap<&String, &T> = old.iter().map(|e| (name(e), e)).collect();
+    let new_by_name: HashMap<&String, &T> = new.iter().map(|e| (name(e), e)).collect();
+
+    let changed = new
+        .iter()
+        .filter(|&e| old_by_name.get(name(e)).map_or(true, |&old| old != e))
+        .cloned()
+        .collect();
+    let removed = old
+        .iter()
+        .map(&name)
+        .filter(|n| !new_by_name.contains_key(n))
+        .cloned()
+        .collect();
+
+    (changed, removed)
+}
+
+/// A local copy of an authd database kept up to date with `changes_since`
+#[derive(Debug, Clone, Default)]
+pub struct Replica {
+    /// Generation this copy matches. `0` before the first sync, which never matches a server.
+    pub generation: u64,
+    pub users: Vec<User>,
+    pub groups: Vec<Group>,
+}
+
+impl Replica {
+    /// Brings this copy up to date, returning false if the changes don't follow on from it
+    pub fn apply(&mut self, changes: Changes) -> bool {
+        match changes {
+            Changes::Resync { generation, dump } => {
+                self.generation = generation;
+                self.users = dump.users;
+                self.groups = dump.groups;
+            }
+            Changes::Diff(diff) => {
+                if diff.from != self.generation {
+                    return false;
+                }
+
+                apply_by_name(&mut self.users, diff.users, &diff.removed_users, |u| {
+                    &u.name
+                });
+                apply_by_name(&mut self.groups, diff.groups, &diff.removed_groups, |g| {
+                    &g.name
+                });
+                self.generation = diff.generation;
+            }
+        }
+        true
+    }
+}
+
+fn apply_by_name<T>(
+    entries: &mut Vec<T>,
+    changed: Vec<T>,
+    removed: &[String],
+    name: impl Fn(&T) -> &String,
+) {
+    entries.retain(|e| !removed.contains(name(e)));
+
+    for entry in changed {
+        match entries.iter().position(|e| name(e) == name(&entry)) {
+            Some(i) => entries[i] = entry,
+            None => entries.push(entry),
+        }
+    }
+}
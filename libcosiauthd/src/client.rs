@@ -0,0 +1,62 @@
+use std::net::SocketAddr;
+
+use anyhow::bail;
+use tokio::net::TcpStream;
+
+use crate::{
+    protocol::{self, PROTOCOL_VERSION},
+    tls::Trust,
+    wire, AuthdClient, ServerHello, WireFormat,
+};
+
+/// Connect to authd over TLS, trusting it according to `trust`.
+///
+/// The server_name is used for SNI and must match the server's certificate.
+///
+/// Returns the client together with the server's answer to `hello`, so callers can check its
+/// capabilities. Fails if the server speaks a protocol version this client can't use.
+pub async fn connect_client(
+    addr: SocketAddr,
+    trust: &Trust,
+    server_name: &str,
+    format: WireFormat,
+) -> anyhow::Result<(AuthdClient, ServerHello)> {
+    let software = concat!("libcosiauthd/", env!("CARGO_PKG_VERSION"));
+
+    let client = open(addr, trust, server_name, format).await?;
+    let (client, server) = match protocol::hello(&client, software).await? {
+        Some(server) => (client, server),
+        // The server predates `hello` and may have hung up on it
+        None => (
+            open(addr, trust, server_name, format).await?,
+            ServerHello::legacy(),
+        ),
+    };
+
+    if format == WireFormat::Bincode && server.protocol_version != PROTOCOL_VERSION {
+        bail!(
+            "server speaks protocol version {} and this client {}; bincode needs both to be the same, use messagepack or json",
+            server.protocol_version,
+            PROTOCOL_VERSION
+        );
+    }
+
+    Ok((client, server))
+}
+
+async fn open(
+    addr: SocketAddr,
+    trust: &Trust,
+    server_name: &str,
+    format: WireFormat,
+) -> anyhow::Result<AuthdClient> {
+    let tcp_stream = TcpStream::connect(addr).await?;
+
+    let servername = rustls::ServerName::try_from(server_name)?;
+    let mut stream = trust.connector().connect(servername, tcp_stream).await?;
+    wire::announce(&mut stream, format).await?;
+
+    let transport = wire::transport(stream, format);
+
+    Ok(AuthdClient::new(tarpc::client::Config::default(), transport).spawn())
+}
@@ -0,0 +1,157 @@
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use crate::{Group, GroupToNSS, Shell, User, UserToNSS};
+
+/// A complete copy of the data served by authd, used for JSON dumps and restores.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dump {
+    pub groups: Vec<Group>,
+    pub users: Vec<User>,
+}
+
+/// Characters that end a field or an entry in `/etc/passwd` and `/etc/group`
+const SEPARATORS: &[char] = &[':', '\n', '\r', '\0'];
+
+/// Fails if `value` contains one of `forbidden`, which would let it spill into other fields or add
+/// entries of its own
+fn check_field(entry: &str, field: &str, value: &str, forbidden: &[char]) -> anyhow::Result<()> {
+    if let Some(c) = value.chars().find(|c| forbidden.contains(c)) {
+        bail!(
+            "{} has {:?} in its {}, which can't be written out",
+            entry,
+            c,
+            field
+        );
+    }
+    Ok(())
+}
+
+/// Renders `users` as the contents of an `/etc/passwd`-style file.
+///
+/// Entries go through `User::to_nss` so the home directory and shell are chosen exactly as the
+/// NSS module would choose them for the same `home_root`, `shells_root` and `shells`. Fails if any
+/// field contains a separator, rather than writing a file that says something else.
+pub fn passwd_file(
+    users: &[User],
+    home_root: &str,
+    shells_root: &str,
+    shells: &Vec<Shell>,
+) -> anyhow::Result<String> {
+    let mut file = String::new();
+
+    for user in users {
+        let p = user.to_nss(home_root, shells_root, shells);
+        let entry = format!("user {:?}", user.name);
+        for (field, value) in [
+            ("name", &p.name),
+            ("gecos", &p.gecos),
+            ("home directory", &p.dir),
+            ("shell", &p.shell),
+        ] {
+            check_field(&entry, field, value, SEPARATORS)?;
+        }
+
+        file += &format!(
+            "{}:{}:{}:{}:{}:{}:{}\n",
+            p.name, p.passwd, p.uid, p.gid, p.gecos, p.dir, p.shell
+        );
+    }
+
+    Ok(file)
+}
+
+/// Renders `groups` as the contents of an `/etc/group`-style file. Fails if any field contains a
+/// separator, including a `,` in a member's name.
+pub fn group_file(groups: &[Group]) -> anyhow::Result<String> {
+    let mut file = String::new();
+
+    for group in groups {
+        let g = group.to_nss();
+        let entry = format!("group {:?}", group.name);
+        check_field(&entry, "name", &g.name, SEPARATORS)?;
+        for member in &g.members {
+            check_field(&entry, "members", member, &[SEPARATORS, &[',']].concat())?;
+        }
+
+        file += &format!(
+            "{}:{}:{}:{}\n",
+            g.name,
+            g.passwd,
+            g.gid,
+            g.members.join(",")
+        );
+    }
+
+    Ok(file)
+}
+
+/// Renders the whole database as pretty printed JSON.
+pub fn json_dump(users: &[User], groups: &[Group]) -> serde_json::Result<String> {
+    serde_json::to_string_pretty(&Dump {
+        groups: groups.to_vec(),
+        users: users.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(name: &str, gecos: Option<&str>) -> User {
+        User {
+            name: name.to_string(),
+            id: 1001,
+            gecos: gecos.map(str::to_string),
+            shells: vec![Shell::Bash],
+        }
+    }
+
+    fn group(name: &str, members: &[&str]) -> Group {
+        Group {
+            name: name.to_string(),
+            gid: 100,
+            members: members.iter().map(|m| m.to_string()).collect(),
+        }
+    }
+
+    fn passwd(users: &[User]) -> anyhow::Result<String> {
+        passwd_file(users, "/home", "/bin", &vec![Shell::Bash])
+    }
+
+    #[test]
+    fn writes_plain_entries() {
+        let file = passwd(&[user("alice", Some("Alice A."))]).unwrap();
+        assert_eq!(file.lines().count(), 1);
+        assert!(file.starts_with("alice:"), "{}", file);
+
+        let file = group_file(&[group("wheel", &["alice", "bob"])]).unwrap();
+        assert!(file.ends_with(":alice,bob\n"), "{}", file);
+    }
+
+    #[test]
+    fn refuses_colons() {
+        assert!(passwd(&[user("alice", Some("Alice:A"))]).is_err());
+        assert!(passwd(&[user("al:ice", None)]).is_err());
+        assert!(group_file(&[group("wh:eel", &[])]).is_err());
+        assert!(group_file(&[group("wheel", &["al:ice"])]).is_err());
+    }
+
+    #[test]
+    fn refuses_newlines() {
+        // Would otherwise add a uid 0 entry to the file
+        let gecos = "x\nevil:x:0:0::/root:/bin/bash";
+        assert!(passwd(&[user("alice", Some(gecos))]).is_err());
+        assert!(passwd(&[user("alice", Some("x\r"))]).is_err());
+        assert!(passwd(&[user("alice\n", None)]).is_err());
+        assert!(group_file(&[group("wheel\n", &[])]).is_err());
+        assert!(group_file(&[group("wheel", &["alice\nroot"])]).is_err());
+    }
+
+    #[test]
+    fn refuses_commas_in_members() {
+        assert!(group_file(&[group("wheel", &["alice,root"])]).is_err());
+        // Commas are only special in the member list
+        assert!(passwd(&[user("alice", Some("Alice, Room 12"))]).is_ok());
+    }
+}
@@ -0,0 +1,510 @@
+//! Choosing between several authd servers.
+//!
+//! Clients are configured with a list of servers. Every address each name resolves to is a
+//! candidate, tried in the configured order (or shuffled, to spread load). A server that fails is
+//! backed off for exponentially longer each time and only tried after the healthy ones. Once its
+//! backoff has passed it is preferred again, so clients that failed over fall forward to it.
+//!
+//! A server given as `srv:<name>` is looked up as a DNS SRV record (RFC 2782). Its targets are
+//! tried by priority, and within a priority in a random order weighted by each record's weight.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+use trust_dns_resolver::{proto::rr::rdata::SRV, TokioAsyncResolver};
+
+use crate::{connect_client, AuthdClient, ServerHello, SocketName, Trust, WireFormat};
+
+/// How long connecting to one server may take unless configured otherwise
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bounds for how long a failed server is skipped
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// The order servers are tried in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailoverOrder {
+    /// As listed, so the first server gets all the traffic while it is up
+    #[default]
+    Ordered,
+    /// Shuffled for every connection, spreading clients over all servers
+    Random,
+}
+
+/// A server [`Failover::connect`] connected to
+#[derive(Debug, Clone)]
+pub struct Connected {
+    pub addr: SocketAddr,
+    pub client: AuthdClient,
+    /// The server's answer to `hello`, saying which methods it can be asked
+    pub server: ServerHello,
+}
+
+/// One or more servers. Configs may give either a single `"host:port"` or a list of them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Servers(Vec<SocketName>);
+
+impl Servers {
+    pub fn names(&self) -> &[SocketName] {
+        &self.0
+    }
+
+    /// Fails if a server's certificate would have no name to be checked against. rustls can't
+    /// check a certificate against an IP address, so servers given by address need `server_name`.
+    pub fn check_server_name(&self, server_name: Option<&str>) -> anyhow::Result<()> {
+        if server_name.is_some() {
+            return Ok(());
+        }
+        let by_addr = self
+            .0
+            .iter()
+            .find(|name| matches!(name, SocketName::Addr(_)));
+        match by_addr {
+            Some(name) => Err(anyhow!(
+                "{} is an IP address, so server_name must be set to the name on its certificate",
+                name
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+impl From<SocketName> for Servers {
+    fn from(name: SocketName) -> Self {
+        Self(vec![name])
+    }
+}
+
+impl<'de> Deserialize<'de> for Servers {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum OneOrMany {
+            One(SocketName),
+            Many(Vec<SocketName>),
+        }
+
+        let names = match OneOrMany::deserialize(deserializer)? {
+            OneOrMany::One(name) => vec![name],
+            OneOrMany::Many(names) => names,
+        };
+        if names.is_empty() {
+            return Err(serde::de::Error::custom("at least one server is needed"));
+        }
+        Ok(Self(names))
+    }
+}
+
+impl std::fmt::Display for Servers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, name) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// One address to try
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub addr: SocketAddr,
+    /// The name the server was found under, or its address for one given by IP address. Its
+    /// certificate is checked against this unless the client configures a `server_name`, which it
+    /// must for servers given by address (see [`Servers::check_server_name`]).
+    pub name: String,
+}
+
+#[derive(Debug)]
+struct Health {
+    failures: u32,
+    retry_at: Instant,
+}
+
+/// Picks which server to connect to and keeps track of the ones that have failed
+pub struct Failover {
+    servers: Servers,
+    order: FailoverOrder,
+    resolver: TokioAsyncResolver,
+    connect_timeout: Duration,
+    health: Mutex<HashMap<SocketAddr, Health>>,
+    /// The candidates from the last time the servers were resolved, before sorting by health
+    resolved: Mutex<Vec<Candidate>>,
+}
+
+impl std::fmt::Debug for Failover {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Failover")
+            .field("servers", &self.servers)
+            .field("order", &self.order)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("health", &self.health)
+            .finish()
+    }
+}
+
+impl Failover {
+    /// Resolves names with the system's DNS configuration
+    pub fn new(servers: Servers, order: FailoverOrder) -> anyhow::Result<Self> {
+        Ok(Self::with_resolver(
+            servers,
+            order,
+            TokioAsyncResolver::tokio_from_system_conf()?,
+        ))
+    }
+
+    /// Resolves names with `resolver`, e.g. one pointed at a local stub server for testing
+    pub fn with_resolver(
+        servers: Servers,
+        order: FailoverOrder,
+        resolver: TokioAsyncResolver,
+    ) -> Self {
+        Self {
+            servers,
+            order,
+            resolver,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            health: Mutex::default(),
+            resolved: Mutex::default(),
+        }
+    }
+
+    /// Gives up on a server that hasn't finished connecting, TLS handshake included, after
+    /// `timeout` and moves on to the next one
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    pub fn servers(&self) -> &Servers {
+        &self.servers
+    }
+
+    /// Resolves every server, returning all their addresses in the order they should be tried.
+    /// The resolver caches answers for as long as their TTL allows, and if no server can be
+    /// resolved at all the addresses found last time are used.
+    pub async fn candidates(&self) -> anyhow::Result<Vec<Candidate>> {
+        let mut groups = Vec::new();
+        let mut errors = Vec::new();
+
+        for name in self.servers.names() {
+            match self.resolve(name).await {
+                Ok(mut addrs) => {
+                    // An SRV record's targets keep the order its priorities and weights gave them
+                    if self.order == FailoverOrder::Random && !matches!(name, SocketName::Srv(_)) {
+                        addrs.shuffle(&mut rand::thread_rng());
+                    }
+                    groups.push(addrs);
+                }
+                Err(err) => errors.push(format!("{}: {:#}", name, err)),
+            }
+        }
+
+        if self.order == FailoverOrder::Random {
+            groups.shuffle(&mut rand::thread_rng());
+        }
+
+        let mut candidates: Vec<Candidate> = groups.into_iter().flatten().collect();
+        if candidates.is_empty() {
+            // While DNS is down, keep using what the servers resolved to last time
+            candidates = self.resolved.lock().unwrap().clone();
+            if candidates.is_empty() {
+                return Err(anyhow!(
+                    "no server could be resolved ({})",
+                    errors.join("; ")
+                ));
+            }
+        } else {
+            *self.resolved.lock().unwrap() = candidates.clone();
+        }
+
+        self.sort(&mut candidates);
+        Ok(candidates)
+    }
+
+    /// Every address `name` stands for, best first
+    async fn resolve(&self, name: &SocketName) -> anyhow::Result<Vec<Candidate>> {
+        Ok(match name {
+            // Never checked against, since configs naming an address need a `server_name`
+            SocketName::Addr(addr) => vec![Candidate {
+                addr: *addr,
+                name: addr.ip().to_string(),
+            }],
+            SocketName::Dns(host, port) => self.lookup(host, *port).await?,
+            SocketName::Srv(service) => {
+                let records = self.resolver.srv_lookup(service.as_str()).await?;
+
+                let mut candidates = Vec::new();
+                let mut errors = Vec::new();
+                for srv in order_srv(records.iter().cloned().collect()) {
+                    let target = srv.target().to_utf8();
+                    match self.lookup(target.trim_end_matches('.'), srv.port()).await {
+                        Ok(found) => candidates.extend(found),
+                        // Another target may still work
+                        Err(err) => {
+                            debug!("{} from {} doesn't resolve: {:#}", target, service, err);
+                            errors.push(format!("{}: {:#}", target, err));
+                        }
+                    }
+                }
+                if candidates.is_empty() && !errors.is_empty() {
+                    return Err(anyhow!("no target resolved ({})", errors.join("; ")));
+                }
+                candidates
+            }
+        })
+    }
+
+    async fn lookup(&self, host: &str, port: u16) -> anyhow::Result<Vec<Candidate>> {
+        Ok(self
+            .resolver
+            .lookup_ip(host)
+            .await?
+            .iter()
+            .map(|ip| Candidate {
+                addr: SocketAddr::new(ip, port),
+                name: host.to_string(),
+            })
+            .collect())
+    }
+
+    /// Moves servers that are backing off behind the rest, soonest to recover first
+    fn sort(&self, candidates: &mut [Candidate]) {
+        let health = self.health.lock().unwrap();
+        let now = Instant::now();
+
+        candidates.sort_by_key(|c| match health.get(&c.addr) {
+            Some(h) if h.retry_at > now => Some(h.retry_at),
+            _ => None,
+        });
+    }
+
+    /// Records that `addr` answered, clearing any backoff
+    pub fn succeeded(&self, addr: SocketAddr) {
+        self.health.lock().unwrap().remove(&addr);
+    }
+
+    /// Records that `addr` couldn't be reached, backing it off
+    pub fn failed(&self, addr: SocketAddr) {
+        let mut health = self.health.lock().unwrap();
+        let entry = health.entry(addr).or_insert(Health {
+            failures: 0,
+            retry_at: Instant::now(),
+        });
+
+        let backoff = MIN_BACKOFF
+            .saturating_mul(1 << entry.failures.min(16))
+            .min(MAX_BACKOFF);
+        entry.failures += 1;
+        entry.retry_at = Instant::now() + backoff;
+    }
+
+    /// Whether a server preferred over `current` has recovered, meaning the caller should
+    /// reconnect to fall forward to it. Uses the addresses from the last [`Failover::candidates`]
+    /// so it is cheap enough to check before every request.
+    pub fn should_fall_forward(&self, current: SocketAddr) -> bool {
+        if self.order == FailoverOrder::Random {
+            return false;
+        }
+
+        let mut candidates = self.resolved.lock().unwrap().clone();
+        self.sort(&mut candidates);
+        candidates
+            .first()
+            .map_or(false, |best| best.addr != current)
+    }
+
+    /// Connects to the first candidate that answers. Certificates are checked against
+    /// `server_name` if given, otherwise against the name each candidate was found under.
+    pub async fn connect(
+        &self,
+        trust: &Trust,
+        server_name: Option<&str>,
+        format: WireFormat,
+    ) -> anyhow::Result<Connected> {
+        let mut errors = Vec::new();
+
+        for candidate in self.candidates().await? {
+            let addr = candidate.addr;
+            let name = server_name.unwrap_or(&candidate.name);
+
+            let attempt = connect_client(addr, trust, name, format);
+            match tokio::time::timeout(self.connect_timeout, attempt).await {
+                Ok(Ok((client, server))) => {
+                    self.succeeded(addr);
+                    return Ok(Connected {
+                        addr,
+                        client,
+                        server,
+                    });
+                }
+                Err(_) => {
+                    self.failed(addr);
+                    errors.push(format!("{}: timed out", addr));
+                }
+                Ok(Err(err)) => {
+                    self.failed(addr);
+                    errors.push(format!("{}: {:#}", addr, err));
+                }
+            }
+        }
+
+        Err(anyhow!("no server answered ({})", errors.join("; ")))
+    }
+}
+
+/// Orders SRV records the way RFC 2782 says clients should try them: by ascending priority, and
+/// within a priority by repeatedly picking a record at random with probability proportional to its
+/// weight.
+pub fn order_srv(records: Vec<SRV>) -> Vec<SRV> {
+    order_srv_with(records, &mut rand::thread_rng())
+}
+
+fn order_srv_with(mut records: Vec<SRV>, rng: &mut impl Rng) -> Vec<SRV> {
+    // A target of "." means the service is deliberately not offered
+    records.retain(|srv| !srv.target().is_root());
+    records.sort_by_key(|srv| srv.priority());
+
+    let mut ordered = Vec::with_capacity(records.len());
+
+    while !records.is_empty() {
+        let priority = records[0].priority();
+        let end = records
+            .iter()
+            .position(|srv| srv.priority() != priority)
+            .unwrap_or(records.len());
+        let mut group: Vec<SRV> = records.drain(..end).collect();
+
+        while !group.is_empty() {
+            let total: u32 = group.iter().map(|srv| srv.weight() as u32).sum();
+            let pick = if total == 0 {
+                rng.gen_range(0..group.len())
+            } else {
+                let mut point = rng.gen_range(0..total);
+                group
+                    .iter()
+                    .position(|srv| {
+                        let weight = srv.weight() as u32;
+                        if point < weight {
+                            true
+                        } else {
+                            point -= weight;
+                            false
+                        }
+                    })
+                    .unwrap_or(0)
+            };
+            ordered.push(group.remove(pick));
+        }
+    }
+
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+    use trust_dns_resolver::proto::rr::Name;
+
+    use super::*;
+
+    #[test]
+    fn addresses_need_a_server_name() {
+        let servers = |names: &[&str]| Servers(names.iter().map(|n| n.parse().unwrap()).collect());
+
+        let by_name = servers(&["auth1.test:8765", "srv:_cosiauthd._tcp.test"]);
+        assert!(by_name.check_server_name(None).is_ok());
+
+        let by_addr = servers(&["auth1.test:8765", "192.0.2.1:8765"]);
+        assert!(by_addr.check_server_name(None).is_err());
+        assert!(by_addr.check_server_name(Some("auth.test")).is_ok());
+    }
+
+    fn srv(priority: u16, weight: u16, target: &str) -> SRV {
+        SRV::new(priority, weight, 8765, Name::from_ascii(target).unwrap())
+    }
+
+    fn targets(records: &[SRV]) -> Vec<String> {
+        records.iter().map(|srv| srv.target().to_utf8()).collect()
+    }
+
+    #[test]
+    fn lower_priorities_come_first() {
+        let records = vec![
+            srv(20, 10, "c.test."),
+            srv(0, 10, "a.test."),
+            srv(10, 10, "b.test."),
+        ];
+        let ordered = order_srv_with(records, &mut StdRng::seed_from_u64(1));
+        assert_eq!(targets(&ordered), ["a.test.", "b.test.", "c.test."]);
+    }
+
+    #[test]
+    fn root_targets_are_dropped() {
+        let records = vec![srv(0, 10, "."), srv(10, 10, "a.test.")];
+        let ordered = order_srv_with(records, &mut StdRng::seed_from_u64(1));
+        assert_eq!(targets(&ordered), ["a.test."]);
+    }
+
+    #[test]
+    fn zero_weights_are_all_tried() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut firsts = HashMap::new();
+
+        for _ in 0..1000 {
+            let records = vec![srv(0, 0, "a.test."), srv(0, 0, "b.test.")];
+            let ordered = order_srv_with(records, &mut rng);
+            assert_eq!(ordered.len(), 2);
+            *firsts.entry(ordered[0].target().to_utf8()).or_insert(0) += 1;
+        }
+
+        // Without weights every target gets a turn at the front
+        assert!(firsts["a.test."] > 400, "{:?}", firsts);
+        assert!(firsts["b.test."] > 400, "{:?}", firsts);
+    }
+
+    #[test]
+    fn zero_weights_follow_weighted_records() {
+        let mut rng = StdRng::seed_from_u64(1);
+
+        for _ in 0..100 {
+            let records = vec![srv(0, 0, "a.test."), srv(0, 1, "b.test.")];
+            let ordered = order_srv_with(records, &mut rng);
+            assert_eq!(targets(&ordered), ["b.test.", "a.test."]);
+        }
+    }
+
+    #[test]
+    fn picks_are_proportional_to_weight() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let rounds = 10_000;
+        let mut heavy_first = 0;
+
+        for _ in 0..rounds {
+            let records = vec![srv(0, 40, "light.test."), srv(0, 60, "heavy.test.")];
+            let ordered = order_srv_with(records, &mut rng);
+            assert_eq!(ordered.len(), 2);
+            if ordered[0].target().to_utf8() == "heavy.test." {
+                heavy_first += 1;
+            }
+        }
+
+        let share = heavy_first as f64 / rounds as f64;
+        assert!((0.57..0.63).contains(&share), "heavy first {}", share);
+    }
+}
@@ -1,11 +1,37 @@
+pub mod attributes;
+pub mod batch;
+mod changes;
+mod client;
+pub mod export;
+pub mod failover;
+pub mod logging;
+pub mod page;
+pub mod protocol;
+pub mod search;
 mod socketname;
+pub mod tls;
 mod types;
+pub mod wire;
 
 pub use types::*;
+pub use attributes::{AttributeError, Attributes, Value};
+pub use changes::{Changes, Diff, Replica};
+pub use client::connect_client;
+pub use failover::{Candidate, Connected, Failover, FailoverOrder, Servers};
+pub use page::{Cursor, Page, PageError};
+pub use protocol::{Hello, ProtocolError, ServerHello};
+pub use search::{Field, MatchMode, Search, SearchError};
 pub use socketname::{SocketName, SocketNameError};
+pub use tls::{Pin, Trust};
+pub use wire::WireFormat;
 
+/// The methods authd serves.
+///
+/// Binary wire formats number methods by their position here, so the order is frozen: methods are
+/// only ever added at the end, with the protocol version that added them. See [`protocol`].
 #[tarpc::service]
 pub trait Authd {
+    // Protocol version 1
     async fn get_all_groups() -> Vec<Group>;
     async fn get_group_by_name(name: String) -> Option<Group>;
     async fn get_group_by_gid(gid: u32) -> Option<Group>;
@@ -13,5 +39,53 @@ pub trait Authd {
     async fn get_all_passwd() -> Vec<User>;
     async fn get_passwd_by_name(name: String) -> Option<User>;
     async fn get_passwd_by_uid(uid: u32) -> Option<User>;
-}
 
+    async fn status() -> Status;
+
+    // Protocol version 2
+    /// Called by clients when they connect. See [`protocol`].
+    async fn hello(client: Hello) -> Result<ServerHello, ProtocolError>;
+
+    // Protocol version 3
+    /// Returns what changed after `generation`. If nothing has changed yet, waits for a change
+    /// until shortly before the request's deadline, so callers can long-poll.
+    async fn changes_since(generation: u64) -> Changes;
+
+    // Protocol version 4
+    /// Lists groups a page at a time, starting from the beginning when `cursor` is `None`. The
+    /// server may return fewer than `limit` entries. See [`page`].
+    async fn list_groups(cursor: Option<Cursor>, limit: u32) -> Result<Page<Group>, PageError>;
+    /// Lists users a page at a time, like `list_groups`
+    async fn list_passwd(cursor: Option<Cursor>, limit: u32) -> Result<Page<User>, PageError>;
+
+    // Protocol version 5
+    /// Looks up many groups in one request. The answer holds one entry per name asked for, in the
+    /// same order, but may stop short of a big batch. See [`batch`].
+    async fn get_groups_by_name(names: Vec<String>) -> Vec<Option<Group>>;
+    /// Like `get_groups_by_name`, by gid
+    async fn get_groups_by_gid(gids: Vec<u32>) -> Vec<Option<Group>>;
+    /// Like `get_groups_by_name`, for users
+    async fn get_passwds_by_name(names: Vec<String>) -> Vec<Option<User>>;
+    /// Like `get_groups_by_name`, for users by uid
+    async fn get_passwds_by_uid(uids: Vec<u32>) -> Vec<Option<User>>;
+
+    // Protocol version 6
+    /// Finds users matching `query`. Only answered for clients presenting one of the server's
+    /// admin tokens. See [`search`].
+    async fn search_users(token: String, query: Search) -> Result<Vec<User>, SearchError>;
+    /// Finds groups matching `query`, like `search_users`
+    async fn search_groups(token: String, query: Search) -> Result<Vec<Group>, SearchError>;
+
+    // Protocol version 7
+    /// Returns the attributes of the user called `name`, or `None` if there is no such user. Only
+    /// answered for clients presenting one of the server's admin tokens. See [`attributes`].
+    async fn get_user_attributes(
+        token: String,
+        name: String,
+    ) -> Result<Option<Attributes>, AttributeError>;
+    /// Like `get_user_attributes`, for groups
+    async fn get_group_attributes(
+        token: String,
+        name: String,
+    ) -> Result<Option<Attributes>, AttributeError>;
+}
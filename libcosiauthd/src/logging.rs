@@ -0,0 +1,174 @@
+//! Log setup shared by authd, the proxy and the NSS module.
+//!
+//! Every component reads a `[log]` section from its config:
+//!
+//! ```toml
+//! [log]
+//! level = "info"        # or filter directives, e.g. "warn,authd=debug"
+//! format = "json"       # or "human" (the default)
+//! names = false         # log user and group names instead of redacting them
+//! ```
+//!
+//! The daemons also take `COSIAUTHD_LOG` and `COSIAUTHD_LOG_FORMAT` from the environment, which
+//! win over the config. The NSS module ignores them, since it runs inside arbitrary programs.
+//! Where the logs go is up to each component. The daemons [`init`] a global subscriber, while the
+//! NSS module keeps the one from [`dispatch`] to itself so the program's own logging is left alone.
+//!
+//! User and group names are personal data that end up in logs on every machine, so unless `names`
+//! is set they are logged through [`name`], which replaces them with a short HMAC under a random
+//! key each process picks at startup and never writes anywhere. Within one process the same name
+//! always gives the same tag, so a daemon's lookups can still be followed through its logs, but
+//! the tags can't be matched to names by hashing guesses, and tags from different processes (or
+//! runs of the same daemon) can't be matched to each other.
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::Dispatch;
+use tracing_subscriber::{fmt::MakeWriter, EnvFilter};
+
+pub const LEVEL_ENV: &str = "COSIAUTHD_LOG";
+pub const FORMAT_ENV: &str = "COSIAUTHD_LOG_FORMAT";
+
+static SHOW_NAMES: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// Key for redacting names, private to this process
+    static ref NAME_KEY: [u8; 32] = rand::random();
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Human,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogConfig {
+    /// A level, or `tracing_subscriber` filter directives
+    #[serde(default = "default_level")]
+    pub level: String,
+    #[serde(default)]
+    pub format: LogFormat,
+    /// Log user and group names in the clear
+    #[serde(default)]
+    pub names: bool,
+}
+
+fn default_level() -> String {
+    "info".to_string()
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: default_level(),
+            format: LogFormat::default(),
+            names: false,
+        }
+    }
+}
+
+/// Who is logging
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// authd or the proxy, writing to a terminal or the journal. The environment may override the
+    /// config.
+    Daemon,
+    /// The NSS module, writing to syslog from inside another program. Lines carry no timestamps or
+    /// colours, since syslog adds its own.
+    Library,
+}
+
+/// The filter and format to log with, from the environment where the target allows it
+fn settings(config: &LogConfig, target: Target) -> anyhow::Result<(EnvFilter, LogFormat)> {
+    let env = |var| {
+        (target == Target::Daemon)
+            .then(|| std::env::var(var).ok())
+            .flatten()
+    };
+
+    let filter = EnvFilter::try_new(env(LEVEL_ENV).unwrap_or_else(|| config.level.clone()))?;
+    let format = match env(FORMAT_ENV) {
+        Some(format) => serde_json::from_value(serde_json::Value::String(format))?,
+        None => config.format,
+    };
+
+    SHOW_NAMES.store(config.names, Ordering::Relaxed);
+    Ok((filter, format))
+}
+
+/// Installs the global subscriber, writing to `writer`, and forwards `log` records to it
+pub fn init<W>(config: &LogConfig, target: Target, writer: W) -> anyhow::Result<()>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let (filter, format) = settings(config, target)?;
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(target == Target::Daemon);
+    match (format, target) {
+        (LogFormat::Human, Target::Daemon) => builder.try_init(),
+        (LogFormat::Human, Target::Library) => builder.without_time().try_init(),
+        (LogFormat::Json, Target::Daemon) => builder.json().try_init(),
+        (LogFormat::Json, Target::Library) => builder.json().without_time().try_init(),
+    }
+    .map_err(|err| anyhow::anyhow!(err))
+}
+
+/// Builds a subscriber writing to `writer` without installing it anywhere, for code loaded into
+/// programs whose global subscriber isn't ours to set. Enter it with
+/// [`tracing::dispatcher::with_default`].
+pub fn dispatch<W>(config: &LogConfig, target: Target, writer: W) -> anyhow::Result<Dispatch>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let (filter, format) = settings(config, target)?;
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(target == Target::Daemon);
+    Ok(match (format, target) {
+        (LogFormat::Human, Target::Daemon) => Dispatch::new(builder.finish()),
+        (LogFormat::Human, Target::Library) => Dispatch::new(builder.without_time().finish()),
+        (LogFormat::Json, Target::Daemon) => Dispatch::new(builder.json().finish()),
+        (LogFormat::Json, Target::Library) => Dispatch::new(builder.json().without_time().finish()),
+    })
+}
+
+/// A user or group name, redacted when logged unless the config allows names
+pub struct Name<'a>(&'a str);
+
+pub fn name(name: &str) -> Name<'_> {
+    Name(name)
+}
+
+impl fmt::Display for Name<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if SHOW_NAMES.load(Ordering::Relaxed) {
+            return write!(f, "{}", self.0);
+        }
+
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&NAME_KEY[..]).expect("HMAC takes keys of any length");
+        mac.update(self.0.as_bytes());
+
+        write!(f, "~")?;
+        for byte in &mac.finalize().into_bytes()[..4] {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+//! Listing every user or group a page at a time.
+//!
+//! `get_all_passwd` and `get_all_groups` send the whole database in one message. `list_passwd` and
+//! `list_groups` instead return a [`Page`] of entries together with a [`Cursor`] for the next one.
+//! A cursor names the generation of the database its listing started from, so every page of a
+//! listing comes from the same generation even if the database is reloaded halfway through. Once
+//! the server no longer has that generation it answers [`PageError::Expired`] and the listing has
+//! to start over.
+
+use serde::{Deserialize, Serialize};
+
+/// Where the next page of a listing starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cursor {
+    pub generation: u64,
+    pub offset: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub entries: Vec<T>,
+    /// `None` once the listing is complete
+    pub next: Option<Cursor>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PageError {
+    /// The generation the cursor points into is gone, so the listing must start over
+    Expired,
+    /// The server couldn't answer, e.g. a proxy that can't reach authd
+    Unavailable,
+}
+
+impl std::fmt::Display for PageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PageError::Expired => write!(f, "the database changed during the listing"),
+            PageError::Unavailable => write!(f, "the server couldn't list entries"),
+        }
+    }
+}
+
+impl std::error::Error for PageError {}
+
+/// Cuts the page starting at `cursor` out of `entries`, which are generation `generation` of the
+/// database. At most `limit` entries are returned, and never more than `max`.
+pub fn page<T: Clone>(
+    entries: &[T],
+    generation: u64,
+    cursor: Option<Cursor>,
+    limit: u32,
+    max: u32,
+) -> Page<T> {
+    let start = cursor.map_or(0, |c| c.offset).min(entries.len());
+    let size = limit.clamp(1, max.max(1)) as usize;
+    let end = (start + size).min(entries.len());
+
+    Page {
+        entries: entries[start..end].to_vec(),
+        next: (end < entries.len()).then_some(Cursor {
+            generation,
+            offset: end,
+        }),
+    }
+}
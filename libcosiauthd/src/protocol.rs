@@ -0,0 +1,186 @@
+//! Versioning for the `Authd` service.
+//!
+//! Clients open every connection with a `hello` so that either side can refuse a peer it can't
+//! talk to, instead of failing on some later lookup.
+//!
+//! To keep mixed deployments working, every field added to a type sent over the wire must be an
+//! `Option` or carry `#[serde(default)]`, and fields must never be removed or renamed. Methods
+//! are only ever added at the end of the `Authd` trait, and need a new entry in [`capability`] so
+//! clients can check before calling them. Servers from before `hello` are treated as speaking
+//! version 1, so clients only call the methods every server has.
+//!
+//! JSON and MessagePack carry field names, so old and new peers can read each other's messages,
+//! ignoring fields they don't know. Bincode carries neither names nor lengths and can't: a field
+//! added to a type makes it unreadable to a peer without it. Clients speaking bincode therefore
+//! refuse servers that don't speak exactly their protocol version, and bincode should only be
+//! turned on where clients and servers are upgraded together.
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tarpc::client::RpcError;
+
+use crate::{Authd, AuthdClient};
+
+/// Version of the protocol spoken by this build.
+///
+/// 1. The original lookups and `status`
+/// 2. `hello`
+/// 3. `changes_since`
+/// 4. `list_groups` and `list_passwd`
+/// 5. Batch lookups
+/// 6. `search_users` and `search_groups`
+/// 7. `get_user_attributes` and `get_group_attributes`
+pub const PROTOCOL_VERSION: u32 = 7;
+
+/// Oldest protocol version a peer may speak and still be served
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional features a server may support, advertised in [`ServerHello::capabilities`]
+pub mod capability {
+    pub const STATUS: &str = "status";
+    pub const BINCODE: &str = "bincode";
+    pub const MESSAGEPACK: &str = "messagepack";
+    pub const CHANGES: &str = "changes";
+    pub const PAGING: &str = "paging";
+    pub const BATCH: &str = "batch";
+    pub const SEARCH: &str = "search";
+    pub const ATTRIBUTES: &str = "attributes";
+}
+
+/// Every capability supported by this build
+pub fn capabilities() -> Vec<String> {
+    [
+        capability::STATUS,
+        capability::BINCODE,
+        capability::MESSAGEPACK,
+        capability::CHANGES,
+        capability::PAGING,
+        capability::BATCH,
+        capability::SEARCH,
+        capability::ATTRIBUTES,
+    ]
+    .iter()
+    .map(|c| c.to_string())
+    .collect()
+}
+
+/// Sent by the client when it connects
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hello {
+    pub protocol_version: u32,
+    /// Name and version of the client software, for logs
+    pub software: String,
+}
+
+impl Hello {
+    pub fn new(software: impl Into<String>) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            software: software.into(),
+        }
+    }
+}
+
+/// The server's answer to a [`Hello`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerHello {
+    pub protocol_version: u32,
+    pub min_protocol_version: u32,
+    /// Name and version of the server software, for logs
+    pub software: String,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+impl ServerHello {
+    pub fn new(software: impl Into<String>) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            software: software.into(),
+            capabilities: capabilities(),
+        }
+    }
+
+    /// Stands in for the answer of a server from before `hello`, which speaks version 1 and
+    /// advertises no capabilities
+    pub fn legacy() -> Self {
+        Self {
+            protocol_version: 1,
+            min_protocol_version: 1,
+            software: "unknown".to_string(),
+            capabilities: Vec::new(),
+        }
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProtocolError {
+    /// The client speaks an older protocol than the server still supports
+    ClientTooOld { client: u32, min: u32 },
+    /// The server speaks an older protocol than the client still supports
+    ServerTooOld { server: u32, min: u32 },
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::ClientTooOld { client, min } => write!(
+                f,
+                "client speaks protocol version {} but the server requires at least {}; upgrade the client",
+                client, min
+            ),
+            ProtocolError::ServerTooOld { server, min } => write!(
+                f,
+                "server speaks protocol version {} but the client requires at least {}; upgrade authd",
+                server, min
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Checks that `req` comes from a client this build can serve
+pub fn check_client(req: &Hello) -> Result<(), ProtocolError> {
+    if req.protocol_version < MIN_PROTOCOL_VERSION {
+        return Err(ProtocolError::ClientTooOld {
+            client: req.protocol_version,
+            min: MIN_PROTOCOL_VERSION,
+        });
+    }
+    Ok(())
+}
+
+/// Introduces a freshly connected client to the server, failing if they can't talk to each other.
+///
+/// A server from before protocol versioning can't read a `hello`, and drops the connection or
+/// answers with an error. Returns `None` then, and the caller has to reconnect, since the
+/// connection may be gone, and treat the server as speaking version 1. A server that doesn't
+/// answer in time is an error like any other, not a sign of its age.
+pub async fn hello(client: &AuthdClient, software: &str) -> anyhow::Result<Option<ServerHello>> {
+    let server = match client
+        .hello(tarpc::context::current(), Hello::new(software))
+        .await
+    {
+        Ok(answer) => answer?,
+        Err(RpcError::Disconnected | RpcError::Server(_)) => return Ok(None),
+        Err(err @ RpcError::DeadlineExceeded) => {
+            return Err(err).context("server didn't answer hello")
+        }
+    };
+
+    if server.protocol_version < MIN_PROTOCOL_VERSION {
+        return Err(ProtocolError::ServerTooOld {
+            server: server.protocol_version,
+            min: MIN_PROTOCOL_VERSION,
+        }
+        .into());
+    }
+
+    Ok(Some(server))
+}
@@ -0,0 +1,311 @@
+//! Searching users and groups, for helpdesk tools.
+//!
+//! Searches may look at names, gecos fields and group membership, so they can turn up people who
+//! couldn't be looked up by name. authd only answers clients that present an admin token, and
+//! never sends more than its configured number of results.
+
+use std::collections::HashSet;
+
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::{Group, User};
+
+/// Longest pattern accepted, so a search can't make the server compile something enormous
+const MAX_PATTERN_LEN: usize = 256;
+
+/// How a search's pattern is matched against a field
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    Prefix,
+    #[default]
+    Substring,
+    /// A regular expression in the syntax of the `regex` crate, matching anywhere unless anchored
+    Regex,
+}
+
+/// Something a search can match against
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Field {
+    Name,
+    /// A user's gecos. Groups have none.
+    Gecos,
+    /// For users, the names of the groups they are in. For groups, the names of their members.
+    Member,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Search {
+    pub pattern: String,
+    #[serde(default)]
+    pub mode: MatchMode,
+    /// An entry matches if any of these fields does. Must not be empty.
+    pub fields: Vec<Field>,
+    #[serde(default)]
+    pub ignore_case: bool,
+    /// Most results wanted. The server may send fewer.
+    pub limit: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SearchError {
+    /// The client didn't present an admin token the server accepts
+    Unauthorized,
+    InvalidPattern(String),
+    /// The server couldn't answer, e.g. a proxy that can't reach authd
+    Unavailable,
+}
+
+impl std::fmt::Display for SearchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SearchError::Unauthorized => write!(f, "searching needs an admin token"),
+            SearchError::InvalidPattern(err) => write!(f, "invalid pattern: {}", err),
+            SearchError::Unavailable => write!(f, "the server couldn't search"),
+        }
+    }
+}
+
+impl std::error::Error for SearchError {}
+
+impl Search {
+    /// Users matching the search, at most `max` of them. `groups` is needed to match membership.
+    pub fn users(
+        &self,
+        users: &[User],
+        groups: &[Group],
+        max: u32,
+    ) -> Result<Vec<User>, SearchError> {
+        let matcher = self.matcher()?;
+
+        // Members of any group whose name matches
+        let in_matching_group: HashSet<&str> = if self.fields.contains(&Field::Member) {
+            groups
+                .iter()
+                .filter(|group| matcher.is_match(&group.name))
+                .flat_map(|group| group.members.iter().map(String::as_str))
+                .collect()
+        } else {
+            HashSet::new()
+        };
+
+        Ok(users
+            .iter()
+            .filter(|user| {
+                self.fields.iter().any(|field| match field {
+                    Field::Name => matcher.is_match(&user.name),
+                    Field::Gecos => user.gecos.as_deref().map_or(false, |g| matcher.is_match(g)),
+                    Field::Member => in_matching_group.contains(user.name.as_str()),
+                })
+            })
+            .take(self.limit.min(max) as usize)
+            .cloned()
+            .collect())
+    }
+
+    /// Groups matching the search, at most `max` of them
+    pub fn groups(&self, groups: &[Group], max: u32) -> Result<Vec<Group>, SearchError> {
+        let matcher = self.matcher()?;
+
+        Ok(groups
+            .iter()
+            .filter(|group| {
+                self.fields.iter().any(|field| match field {
+                    Field::Name => matcher.is_match(&group.name),
+                    Field::Gecos => false,
+                    Field::Member => group.members.iter().any(|m| matcher.is_match(m)),
+                })
+            })
+            .take(self.limit.min(max) as usize)
+            .cloned()
+            .collect())
+    }
+
+    /// Every mode is turned into a regex, so they all treat case the same way
+    fn matcher(&self) -> Result<Regex, SearchError> {
+        // Nothing would ever match, which is surely not what was meant
+        if self.fields.is_empty() {
+            return Err(SearchError::InvalidPattern(
+                "no fields to search".to_string(),
+            ));
+        }
+        if self.pattern.len() > MAX_PATTERN_LEN {
+            return Err(SearchError::InvalidPattern(format!(
+                "longer than {} bytes",
+                MAX_PATTERN_LEN
+            )));
+        }
+
+        let pattern = match self.mode {
+            MatchMode::Prefix => format!("^{}", regex::escape(&self.pattern)),
+            MatchMode::Substring => regex::escape(&self.pattern),
+            MatchMode::Regex => self.pattern.clone(),
+        };
+
+        RegexBuilder::new(&pattern)
+            .case_insensitive(self.ignore_case)
+            .size_limit(1 << 20)
+            .build()
+            .map_err(|err| SearchError::InvalidPattern(err.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search(pattern: &str, mode: MatchMode, fields: &[Field]) -> Search {
+        Search {
+            pattern: pattern.to_string(),
+            mode,
+            fields: fields.to_vec(),
+            ignore_case: false,
+            limit: 100,
+        }
+    }
+
+    fn user(name: &str, gecos: &str) -> User {
+        User {
+            name: name.to_string(),
+            id: 1000,
+            gecos: Some(gecos.to_string()),
+            shells: vec![],
+        }
+    }
+
+    fn group(name: &str, members: &[&str]) -> Group {
+        Group {
+            name: name.to_string(),
+            gid: 100,
+            members: members.iter().map(|m| m.to_string()).collect(),
+        }
+    }
+
+    fn users() -> Vec<User> {
+        vec![
+            user("alice", "Alice Liddell"),
+            user("bob", "Bob (a.k.a. b.b)"),
+            user("carol", "Carol Alice"),
+        ]
+    }
+
+    fn groups() -> Vec<Group> {
+        vec![
+            group("wheel", &["alice"]),
+            group("staff", &["bob", "carol"]),
+        ]
+    }
+
+    fn names<T>(found: &[T], name: impl Fn(&T) -> &str) -> Vec<&str> {
+        found.iter().map(name).collect()
+    }
+
+    #[test]
+    fn modes() {
+        let users = users();
+        let found = |s: Search| {
+            let found = s.users(&users, &[], 100).unwrap();
+            names(&found, |u| u.name.as_str()).join(",")
+        };
+
+        assert_eq!(
+            found(search("al", MatchMode::Prefix, &[Field::Name])),
+            "alice"
+        );
+        assert_eq!(
+            found(search("Alice", MatchMode::Substring, &[Field::Gecos])),
+            "alice,carol"
+        );
+        assert_eq!(
+            found(search("^[bc]", MatchMode::Regex, &[Field::Name])),
+            "bob,carol"
+        );
+    }
+
+    #[test]
+    fn prefix_and_substring_are_literal() {
+        let users = users();
+
+        let dots = search("a.k.a", MatchMode::Substring, &[Field::Gecos]);
+        assert_eq!(
+            names(&dots.users(&users, &[], 100).unwrap(), |u| u.name.as_str()),
+            ["bob"]
+        );
+
+        // Unescaped, `.` would match any character and `(` wouldn't compile
+        let any = search("Bob (", MatchMode::Prefix, &[Field::Gecos]);
+        assert_eq!(
+            names(&any.users(&users, &[], 100).unwrap(), |u| u.name.as_str()),
+            ["bob"]
+        );
+        let any = search("a.i", MatchMode::Substring, &[Field::Name]);
+        assert!(any.users(&users, &[], 100).unwrap().is_empty());
+    }
+
+    #[test]
+    fn ignore_case() {
+        let users = users();
+        let mut s = search("ALICE", MatchMode::Substring, &[Field::Name]);
+        assert!(s.users(&users, &[], 100).unwrap().is_empty());
+
+        s.ignore_case = true;
+        assert_eq!(
+            names(&s.users(&users, &[], 100).unwrap(), |u| u.name.as_str()),
+            ["alice"]
+        );
+    }
+
+    #[test]
+    fn members() {
+        let (users, groups) = (users(), groups());
+
+        // Users are found through the names of their groups
+        let s = search("staff", MatchMode::Substring, &[Field::Member]);
+        assert_eq!(
+            names(&s.users(&users, &groups, 100).unwrap(), |u| u.name.as_str()),
+            ["bob", "carol"]
+        );
+
+        // Groups through the names of their members
+        let s = search("alice", MatchMode::Substring, &[Field::Member]);
+        assert_eq!(
+            names(&s.groups(&groups, 100).unwrap(), |g| g.name.as_str()),
+            ["wheel"]
+        );
+
+        // Groups have no gecos
+        let s = search("alice", MatchMode::Substring, &[Field::Gecos]);
+        assert!(s.groups(&groups, 100).unwrap().is_empty());
+    }
+
+    #[test]
+    fn limits() {
+        let users = users();
+        let mut s = search("", MatchMode::Substring, &[Field::Name]);
+
+        s.limit = 2;
+        assert_eq!(s.users(&users, &[], 100).unwrap().len(), 2);
+        s.limit = 100;
+        assert_eq!(s.users(&users, &[], 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn bad_searches_are_rejected() {
+        let invalid =
+            |s: Search| matches!(s.users(&[], &[], 100), Err(SearchError::InvalidPattern(_)));
+
+        assert!(invalid(search("(", MatchMode::Regex, &[Field::Name])));
+        assert!(invalid(search("a", MatchMode::Substring, &[])));
+
+        let long = "a".repeat(MAX_PATTERN_LEN + 1);
+        assert!(invalid(search(&long, MatchMode::Substring, &[Field::Name])));
+        let longest = "a".repeat(MAX_PATTERN_LEN);
+        assert!(!invalid(search(
+            &longest,
+            MatchMode::Substring,
+            &[Field::Name]
+        )));
+    }
+}
@@ -1,15 +1,18 @@
 use std::{net::SocketAddr, num::ParseIntError, str::FromStr};
 
-/// SocketName represents a socket address as either a `std::net::SocketAddr` or a domain name + a
-/// port.
+/// SocketName represents a socket address as either a `std::net::SocketAddr`, a domain name + a
+/// port, or a DNS SRV record naming the servers.
 ///
 /// For example:
 /// `domain.com:1234`
 /// `127.0.0.1:44`
-#[derive(Debug, PartialEq, Eq)]
+/// `srv:_cosiauthd._tcp.domain.com`
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum SocketName {
     Dns(String, u16),
     Addr(SocketAddr),
+    /// Name of an SRV record, resolved by [`crate::Failover`]
+    Srv(String),
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -24,7 +27,7 @@ impl std::fmt::Display for SocketNameError {
             SocketNameError::ParseIntError(err) => write!(f, "{}", err)?,
             SocketNameError::FormatError() => write!(
                 f,
-                "Doesn't match the either format. Expect something like 'auth.cosi.clarkson.edu:8765' or '128.153.145.3'."
+                "Doesn't match any format. Expect something like 'auth.cosi.clarkson.edu:8765', '128.153.145.3:8765' or 'srv:_cosiauthd._tcp.cosi.clarkson.edu'."
             )?,
         })
     }
@@ -34,6 +37,10 @@ impl FromStr for SocketName {
     type Err = SocketNameError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(name) = s.strip_prefix("srv:") {
+            return Ok(SocketName::Srv(name.into()));
+        }
+
         match SocketAddr::from_str(s) {
             Ok(sa) => Ok(SocketName::Addr(sa)),
             Err(_) => {
@@ -52,6 +59,16 @@ impl FromStr for SocketName {
     }
 }
 
+impl std::fmt::Display for SocketName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SocketName::Dns(host, port) => write!(f, "{}:{}", host, port),
+            SocketName::Addr(sa) => write!(f, "{}", sa),
+            SocketName::Srv(name) => write!(f, "srv:{}", name),
+        }
+    }
+}
+
 impl<'de> serde::Deserialize<'de> for SocketName {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -63,6 +80,15 @@ impl<'de> serde::Deserialize<'de> for SocketName {
     }
 }
 
+impl serde::Serialize for SocketName {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
 impl std::net::ToSocketAddrs for SocketName {
     type Iter = std::vec::IntoIter<SocketAddr>;
 
@@ -74,6 +100,12 @@ impl std::net::ToSocketAddrs for SocketName {
                 .collect::<Vec<_>>()
                 .into_iter(),
             SocketName::Addr(sa) => vec![*sa].into_iter(),
+            SocketName::Srv(name) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!("{} is an SRV record, which needs Failover to resolve", name),
+                ))
+            }
         })
     }
 }
@@ -0,0 +1,275 @@
+//! How clients decide to trust an authd server.
+//!
+//! The `cert` file in a client config may hold the server's own certificate or the CA(s) that
+//! issued it, in DER or PEM form. A PEM file may contain any number of certificates, so a CA bundle
+//! works too. The name checked against the certificate comes from `server_name` in the config, or
+//! otherwise the name the server was found under. Servers given by IP address have no name to go
+//! by, so configs naming them must set `server_name`.
+//!
+//! Certificates can additionally be pinned by the SHA-256 of the whole certificate
+//! (`cert-sha256:<hex>`) or of its public key (`spki-sha256:<hex>`). A connection is only accepted
+//! if some certificate in the chain the server presents matches a pin. Pinning the key of an
+//! intermediate CA that authd sends along with its certificate lets server certificates be rotated
+//! without touching clients.
+
+use std::{fs, path::Path, str::FromStr, sync::Arc, time::SystemTime};
+
+use anyhow::{anyhow, bail, Context};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    Certificate, ClientConfig, RootCertStore, ServerName,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio_rustls::TlsConnector;
+
+/// Reads every certificate in `path`, which may be DER or PEM
+pub fn load_certs(path: impl AsRef<Path>) -> anyhow::Result<Vec<Certificate>> {
+    let path = path.as_ref();
+    let contents = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+
+    if !contents.starts_with(b"-----BEGIN") {
+        return Ok(vec![Certificate(contents)]);
+    }
+
+    let certs = rustls_pemfile::certs(&mut &contents[..])
+        .with_context(|| format!("parsing {}", path.display()))?;
+    if certs.is_empty() {
+        bail!("{} contains no certificates", path.display());
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// A SHA-256 fingerprint a server's certificate chain must match
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pin {
+    /// Of the whole DER certificate
+    Cert([u8; 32]),
+    /// Of the certificate's SubjectPublicKeyInfo, which survives reissuing with the same key
+    Spki([u8; 32]),
+}
+
+impl Pin {
+    fn matches(&self, cert: &Certificate) -> bool {
+        match self {
+            Pin::Cert(hash) => Sha256::digest(&cert.0)[..] == hash[..],
+            Pin::Spki(hash) => match x509_parser::parse_x509_certificate(&cert.0) {
+                Ok((_, parsed)) => {
+                    Sha256::digest(parsed.tbs_certificate.subject_pki.raw)[..] == hash[..]
+                }
+                Err(_) => false,
+            },
+        }
+    }
+}
+
+impl FromStr for Pin {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, hex) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("expected cert-sha256:<hex> or spki-sha256:<hex>"))?;
+
+        // Fingerprints are often copied with colons between the bytes
+        let hex: String = hex.chars().filter(|c| *c != ':').collect();
+        if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("a SHA-256 fingerprint is 64 hex digits");
+        }
+        let mut hash = [0; 32];
+        for (byte, pair) in hash.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            // Both bytes are ASCII hex digits, checked above
+            *byte = u8::from_str_radix(std::str::from_utf8(pair)?, 16)?;
+        }
+
+        match kind {
+            "cert-sha256" => Ok(Pin::Cert(hash)),
+            "spki-sha256" => Ok(Pin::Spki(hash)),
+            other => bail!("unknown pin type {}", other),
+        }
+    }
+}
+
+impl std::fmt::Display for Pin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (kind, hash) = match self {
+            Pin::Cert(hash) => ("cert-sha256", hash),
+            Pin::Spki(hash) => ("spki-sha256", hash),
+        };
+        write!(f, "{}:", kind)?;
+        for byte in hash {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for Pin {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+        let s = String::deserialize(deserializer)?;
+        Pin::from_str(&s).map_err(|e| D::Error::custom(e.to_string()))
+    }
+}
+
+impl Serialize for Pin {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+/// The certificates and pins a client trusts
+#[derive(Clone)]
+pub struct Trust {
+    config: Arc<ClientConfig>,
+}
+
+impl std::fmt::Debug for Trust {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Trust").finish_non_exhaustive()
+    }
+}
+
+impl Trust {
+    /// Trusts servers whose chain leads to one of `roots` and, if there are any, matches one of
+    /// `pins`
+    pub fn new(roots: &[Certificate], pins: Vec<Pin>) -> anyhow::Result<Self> {
+        let mut store = RootCertStore::empty();
+        for cert in roots {
+            store.add(cert)?;
+        }
+
+        let builder = ClientConfig::builder().with_safe_defaults();
+        let config = if pins.is_empty() {
+            builder.with_root_certificates(store).with_no_client_auth()
+        } else {
+            builder
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                    inner: WebPkiVerifier::new(store, None),
+                    pins,
+                }))
+                .with_no_client_auth()
+        };
+
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Trusts the certificates in the file at `path` (see [`load_certs`])
+    pub fn load(path: impl AsRef<Path>, pins: Vec<Pin>) -> anyhow::Result<Self> {
+        Self::new(&load_certs(path)?, pins)
+    }
+
+    pub(crate) fn connector(&self) -> TlsConnector {
+        TlsConnector::from(self.config.clone())
+    }
+}
+
+/// Checks the chain as usual, then that it matches a pin
+struct PinnedVerifier {
+    inner: WebPkiVerifier,
+    pins: Vec<Pin>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+
+        let pinned = std::iter::once(end_entity)
+            .chain(intermediates)
+            .any(|cert| self.pins.iter().any(|pin| pin.matches(cert)));
+        if !pinned {
+            return Err(rustls::Error::General(
+                "server certificate doesn't match any pin".into(),
+            ));
+        }
+
+        Ok(verified)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    fn hash() -> [u8; 32] {
+        let mut hash = [0; 32];
+        for (i, byte) in hash.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&HEX[i * 2..i * 2 + 2], 16).unwrap();
+        }
+        hash
+    }
+
+    #[test]
+    fn parses_pins() {
+        let pin: Pin = format!("spki-sha256:{}", HEX).parse().unwrap();
+        assert_eq!(pin, Pin::Spki(hash()));
+        assert_eq!(pin.to_string(), format!("spki-sha256:{}", HEX));
+
+        let pin: Pin = format!("cert-sha256:{}", HEX.to_uppercase())
+            .parse()
+            .unwrap();
+        assert_eq!(pin, Pin::Cert(hash()));
+    }
+
+    #[test]
+    fn parses_colon_separated_pins() {
+        let pairs: Vec<&str> = (0..32).map(|i| &HEX[i * 2..i * 2 + 2]).collect();
+        let pin: Pin = format!("cert-sha256:{}", pairs.join(":")).parse().unwrap();
+        assert_eq!(pin, Pin::Cert(hash()));
+    }
+
+    #[test]
+    fn rejects_short_pins() {
+        assert!(format!("cert-sha256:{}", &HEX[..62])
+            .parse::<Pin>()
+            .is_err());
+        assert!("cert-sha256:".parse::<Pin>().is_err());
+    }
+
+    #[test]
+    fn rejects_non_ascii_pins() {
+        // 64 bytes, but "é" is two of them
+        let pin = format!("cert-sha256:{}é", &HEX[..62]);
+        assert_eq!(pin.len() - "cert-sha256:".len(), 64);
+        assert!(pin.parse::<Pin>().is_err());
+
+        // Here "é" straddles two hex pairs
+        let pin = format!("cert-sha256:{}é{}", &HEX[..31], &HEX[..31]);
+        assert_eq!(pin.len() - "cert-sha256:".len(), 64);
+        assert!(pin.parse::<Pin>().is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_kinds_and_bad_digits() {
+        assert!(format!("md5:{}", HEX).parse::<Pin>().is_err());
+        assert!(HEX.parse::<Pin>().is_err());
+        assert!(format!("cert-sha256:{}zz", &HEX[..62])
+            .parse::<Pin>()
+            .is_err());
+    }
+}
@@ -1,9 +1,10 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Group {
     pub name: String,
     pub gid: u32,
+    #[serde(default)]
     pub members: Vec<String>,
 }
 
@@ -34,10 +35,11 @@ impl GroupToNSS for Vec<Group> {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct User {
     pub name: String,
     pub id: u32,
+    #[serde(default)]
     pub gecos: Option<String>,
     #[serde(default)]
     pub shells: Vec<Shell>,
@@ -113,3 +115,49 @@ impl std::fmt::Display for Shell {
         write!(f, "{}", s)
     }
 }
+
+/// Health information reported by the `status` RPC
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    /// Version of the server (or proxy) answering
+    pub version: String,
+    pub uptime_secs: u64,
+    /// The database being served. Only `None` when a proxy can't reach its upstream.
+    pub database: Option<DatabaseStatus>,
+    /// Only set when the answer comes from a proxy
+    pub upstream: Option<UpstreamStatus>,
+    /// Only set when the database comes from an authd running as a replica
+    #[serde(default)]
+    pub replication: Option<ReplicationStatus>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DatabaseStatus {
+    /// Incremented every time authd loads a new config
+    pub generation: u64,
+    /// Hex encoded SHA-256 of the config file
+    pub config_hash: String,
+    /// Unix time of the last successful (re)load
+    pub last_reload: u64,
+    pub users: usize,
+    pub groups: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpstreamStatus {
+    pub host: String,
+    /// Whether the last request to the upstream server succeeded
+    pub connected: bool,
+    /// Age of the proxy's cached copy of the database, if it keeps one
+    pub cache_age_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicationStatus {
+    pub primary: String,
+    /// Whether the replica is currently following the primary
+    pub connected: bool,
+    /// Seconds since the replica last applied an answer from the primary, so was last known to
+    /// match it. `None` before the first sync.
+    pub lag_secs: Option<u64>,
+}
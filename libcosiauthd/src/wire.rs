@@ -0,0 +1,147 @@
+//! Serialization formats for the tarpc transports between authd, the proxy and clients.
+//!
+//! JSON is the original format and is what a connection speaks unless the client asks for
+//! something else. A client that wants a binary format writes a single preamble byte right after
+//! connecting (after the TLS handshake when there is one). Servers look at the first byte a client
+//! sends to decide: tarpc frames start with a 4 byte big-endian length, so an old JSON client's
+//! first byte is always `0` and is left in place, while the preamble bytes below are never `0`.
+//!
+//! This means servers must be upgraded before any client is configured with a binary format.
+//! Clients left on JSON keep working against old and new servers alike.
+
+use std::{error::Error, io, pin::Pin};
+
+use bytes::{Bytes, BytesMut};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tarpc::{
+    serde_transport::Transport,
+    tokio_serde::{Deserializer, Serializer},
+};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+
+const PREAMBLE_BINCODE: u8 = 0xb1;
+const PREAMBLE_MESSAGEPACK: u8 = 0xb2;
+
+/// A serialization format for tarpc messages
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    #[default]
+    Json,
+    /// Smallest and fastest, but both sides must agree on the exact shape of every message
+    Bincode,
+    /// Compact, and like JSON tolerates fields being added to messages
+    MessagePack,
+}
+
+impl std::fmt::Display for WireFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            WireFormat::Json => "json",
+            WireFormat::Bincode => "bincode",
+            WireFormat::MessagePack => "messagepack",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl WireFormat {
+    fn preamble(self) -> Option<u8> {
+        match self {
+            WireFormat::Json => None,
+            WireFormat::Bincode => Some(PREAMBLE_BINCODE),
+            WireFormat::MessagePack => Some(PREAMBLE_MESSAGEPACK),
+        }
+    }
+}
+
+/// Tells the server which format the client is going to speak. Called by clients right after
+/// connecting.
+pub async fn announce<S: AsyncWrite + Unpin>(stream: &mut S, format: WireFormat) -> io::Result<()> {
+    if let Some(byte) = format.preamble() {
+        stream.write_all(&[byte]).await?;
+        stream.flush().await?;
+    }
+    Ok(())
+}
+
+/// Works out which format a newly connected client speaks, consuming its preamble if it sent one.
+///
+/// The stream is returned wrapped in a `BufReader` since the first byte has to be peeked at.
+pub async fn negotiate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+) -> io::Result<(BufReader<S>, WireFormat)> {
+    let mut stream = BufReader::new(stream);
+
+    let first = match stream.fill_buf().await?.first() {
+        Some(byte) => *byte,
+        None => return Err(io::ErrorKind::UnexpectedEof.into()),
+    };
+
+    let format = match first {
+        PREAMBLE_BINCODE => WireFormat::Bincode,
+        PREAMBLE_MESSAGEPACK => WireFormat::MessagePack,
+        0 => return Ok((stream, WireFormat::Json)),
+        other => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown wire format preamble {:#04x}", other),
+            ))
+        }
+    };
+    stream.consume(1);
+
+    Ok((stream, format))
+}
+
+/// Builds a tarpc transport over `io` speaking `format`
+pub fn transport<S, Item, SinkItem>(
+    io: S,
+    format: WireFormat,
+) -> Transport<S, Item, SinkItem, Codec>
+where
+    S: AsyncRead + AsyncWrite,
+    Item: DeserializeOwned,
+    SinkItem: Serialize,
+{
+    Transport::from((io, Codec::new(format)))
+}
+
+/// A `tokio_serde` codec that speaks any [`WireFormat`]
+#[derive(Debug, Clone, Copy)]
+pub struct Codec(WireFormat);
+
+impl Codec {
+    pub fn new(format: WireFormat) -> Self {
+        Self(format)
+    }
+}
+
+fn invalid_data(err: impl Into<Box<dyn Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+impl<T: Serialize> Serializer<T> for Codec {
+    type Error = io::Error;
+
+    fn serialize(self: Pin<&mut Self>, item: &T) -> Result<Bytes, Self::Error> {
+        let bytes = match self.0 {
+            WireFormat::Json => serde_json::to_vec(item).map_err(invalid_data)?,
+            WireFormat::Bincode => bincode::serialize(item).map_err(invalid_data)?,
+            WireFormat::MessagePack => rmp_serde::to_vec_named(item).map_err(invalid_data)?,
+        };
+        Ok(Bytes::from(bytes))
+    }
+}
+
+impl<T: DeserializeOwned> Deserializer<T> for Codec {
+    type Error = io::Error;
+
+    fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> Result<T, Self::Error> {
+        match self.0 {
+            WireFormat::Json => serde_json::from_slice(src).map_err(invalid_data),
+            WireFormat::Bincode => bincode::deserialize(src).map_err(invalid_data),
+            WireFormat::MessagePack => rmp_serde::from_slice(src).map_err(invalid_data),
+        }
+    }
+}
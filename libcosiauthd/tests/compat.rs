@@ -0,0 +1,207 @@
+//! Old and new peers reading each other's messages, in every wire format.
+//!
+//! `v1` holds frozen copies of the messages of protocol version 1, as the original clients and
+//! servers were built. Never change it to follow the current types.
+
+use std::{io, pin::Pin};
+
+use bytes::BytesMut;
+use libcosiauthd::{
+    protocol::{self, ProtocolError, MIN_PROTOCOL_VERSION},
+    wire::Codec,
+    Attributes, AuthdRequest, Group, Hello, Shell, User, WireFormat,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use tarpc::tokio_serde::{Deserializer, Serializer};
+
+mod v1 {
+    use libcosiauthd::Shell;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct Group {
+        pub name: String,
+        pub gid: u32,
+        pub members: Vec<String>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct User {
+        pub name: String,
+        pub id: u32,
+        pub gecos: Option<String>,
+        #[serde(default)]
+        pub shells: Vec<Shell>,
+    }
+
+    /// The requests tarpc generated for the version 1 `Authd` trait
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub enum AuthdRequest {
+        GetAllGroups {},
+        GetGroupByName { name: String },
+        GetGroupByGid { gid: u32 },
+        GetAllPasswd {},
+        GetPasswdByName { name: String },
+        GetPasswdByUid { uid: u32 },
+        Status {},
+    }
+}
+
+const FORMATS: [WireFormat; 3] = [
+    WireFormat::Json,
+    WireFormat::Bincode,
+    WireFormat::MessagePack,
+];
+
+/// Encodes `item` the way one peer would send it and decodes it the way the other would read it
+fn send<A: Serialize, B: DeserializeOwned>(format: WireFormat, item: &A) -> io::Result<B> {
+    let mut codec = Codec::new(format);
+    let bytes = Serializer::<A>::serialize(Pin::new(&mut codec), item)?;
+    Deserializer::<B>::deserialize(Pin::new(&mut codec), &BytesMut::from(&bytes[..]))
+}
+
+fn v1_user() -> v1::User {
+    v1::User {
+        name: "alice".to_string(),
+        id: 1001,
+        gecos: Some("Alice".to_string()),
+        shells: vec![Shell::Bash, Shell::Sh],
+    }
+}
+
+fn v1_group() -> v1::Group {
+    v1::Group {
+        name: "sysadmins".to_string(),
+        gid: 2000,
+        members: vec!["alice".to_string(), "bob".to_string()],
+    }
+}
+
+#[test]
+fn users_cross_versions_in_every_format() {
+    for format in FORMATS {
+        let user: User = send(format, &v1_user()).unwrap();
+        assert_eq!(user.name, "alice", "{}", format);
+        assert_eq!(user.id, 1001, "{}", format);
+        assert_eq!(user.gecos.as_deref(), Some("Alice"), "{}", format);
+        assert_eq!(user.shells, vec![Shell::Bash, Shell::Sh], "{}", format);
+
+        let old: v1::User = send(format, &user).unwrap();
+        assert_eq!(old, v1_user(), "{}", format);
+    }
+}
+
+#[test]
+fn groups_cross_versions_in_every_format() {
+    for format in FORMATS {
+        let group: Group = send(format, &v1_group()).unwrap();
+        assert_eq!(group.name, "sysadmins", "{}", format);
+        assert_eq!(group.gid, 2000, "{}", format);
+        assert_eq!(group.members, ["alice", "bob"], "{}", format);
+
+        let old: v1::Group = send(format, &group).unwrap();
+        assert_eq!(old, v1_group(), "{}", format);
+    }
+}
+
+#[test]
+fn v1_requests_reach_the_same_methods() {
+    let requests = [
+        v1::AuthdRequest::GetAllGroups {},
+        v1::AuthdRequest::GetGroupByName {
+            name: "sysadmins".to_string(),
+        },
+        v1::AuthdRequest::GetGroupByGid { gid: 2000 },
+        v1::AuthdRequest::GetAllPasswd {},
+        v1::AuthdRequest::GetPasswdByName {
+            name: "alice".to_string(),
+        },
+        v1::AuthdRequest::GetPasswdByUid { uid: 1001 },
+        v1::AuthdRequest::Status {},
+    ];
+
+    for format in FORMATS {
+        for old in &requests {
+            let new: AuthdRequest = send(format, old).unwrap();
+            let matches = match (old, &new) {
+                (v1::AuthdRequest::GetAllGroups {}, AuthdRequest::GetAllGroups {}) => true,
+                (
+                    v1::AuthdRequest::GetGroupByName { name: a },
+                    AuthdRequest::GetGroupByName { name: b },
+                ) => a == b,
+                (
+                    v1::AuthdRequest::GetGroupByGid { gid: a },
+                    AuthdRequest::GetGroupByGid { gid: b },
+                ) => a == b,
+                (v1::AuthdRequest::GetAllPasswd {}, AuthdRequest::GetAllPasswd {}) => true,
+                (
+                    v1::AuthdRequest::GetPasswdByName { name: a },
+                    AuthdRequest::GetPasswdByName { name: b },
+                ) => a == b,
+                (
+                    v1::AuthdRequest::GetPasswdByUid { uid: a },
+                    AuthdRequest::GetPasswdByUid { uid: b },
+                ) => a == b,
+                (v1::AuthdRequest::Status {}, AuthdRequest::Status {}) => true,
+                _ => false,
+            };
+            assert!(matches, "{}: {:?} arrived as {:?}", format, old, new);
+
+            let back: v1::AuthdRequest = send(format, &new).unwrap();
+            assert_eq!(&back, old, "{}", format);
+        }
+    }
+}
+
+/// Attributes as a later version might send them, with a field this one doesn't have
+#[derive(Serialize)]
+struct NewerAttributes {
+    email: Option<String>,
+    class_year: Option<i64>,
+    office: Option<String>,
+}
+
+#[test]
+fn newer_attributes_are_read_in_self_describing_formats() {
+    let newer = NewerAttributes {
+        email: Some("alice@example.edu".to_string()),
+        class_year: Some(2027),
+        office: Some("SC 336".to_string()),
+    };
+
+    for format in [WireFormat::Json, WireFormat::MessagePack] {
+        let attributes: Attributes = send(format, &newer).unwrap();
+        assert_eq!(
+            attributes.email.as_deref(),
+            Some("alice@example.edu"),
+            "{}",
+            format
+        );
+        assert_eq!(attributes.class_year, Some(2027), "{}", format);
+        assert!(attributes.custom.is_empty(), "{}", format);
+    }
+}
+
+#[test]
+fn too_old_clients_are_refused() {
+    let old = Hello {
+        protocol_version: MIN_PROTOCOL_VERSION - 1,
+        software: "ancient".to_string(),
+    };
+    assert_eq!(
+        protocol::check_client(&old),
+        Err(ProtocolError::ClientTooOld {
+            client: MIN_PROTOCOL_VERSION - 1,
+            min: MIN_PROTOCOL_VERSION,
+        })
+    );
+
+    let current = Hello::new("current");
+    assert_eq!(protocol::check_client(&current), Ok(()));
+
+    let oldest = Hello {
+        protocol_version: MIN_PROTOCOL_VERSION,
+        software: "oldest".to_string(),
+    };
+    assert_eq!(protocol::check_client(&oldest), Ok(()));
+}
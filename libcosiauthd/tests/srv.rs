@@ -0,0 +1,137 @@
+//! Finding servers through SRV records, against a stub nameserver on loopback.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use libcosiauthd::{Failover, FailoverOrder, Servers, SocketName};
+use tokio::net::UdpSocket;
+use trust_dns_resolver::{
+    config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    proto::{
+        op::{Message, MessageType, ResponseCode},
+        rr::{rdata::SRV, Name, RData, Record, RecordType},
+        serialize::binary::BinEncodable,
+    },
+    TokioAsyncResolver,
+};
+
+const SERVICE: &str = "_cosiauthd._tcp.test.";
+
+/// The SRV records served for `SERVICE`: two servers sharing the lowest priority, one behind
+/// them, and one saying the service isn't offered, which must be ignored
+fn srv_records() -> Vec<SRV> {
+    vec![
+        SRV::new(0, 60, 8765, Name::from_ascii("auth1.test.").unwrap()),
+        SRV::new(0, 40, 8765, Name::from_ascii("auth2.test.").unwrap()),
+        SRV::new(10, 0, 8766, Name::from_ascii("auth3.test.").unwrap()),
+        SRV::new(5, 0, 8765, Name::root()),
+    ]
+}
+
+fn host_ip(name: &str) -> Option<Ipv4Addr> {
+    match name {
+        "auth1.test." => Some(Ipv4Addr::new(127, 0, 0, 1)),
+        "auth2.test." => Some(Ipv4Addr::new(127, 0, 0, 2)),
+        "auth3.test." => Some(Ipv4Addr::new(127, 0, 0, 3)),
+        _ => None,
+    }
+}
+
+fn answer(request: &Message) -> Message {
+    let mut response = Message::new();
+    response
+        .set_id(request.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request.op_code())
+        .set_recursion_desired(request.recursion_desired())
+        .set_recursion_available(true)
+        .set_response_code(ResponseCode::NoError);
+
+    for query in request.queries() {
+        response.add_query(query.clone());
+        let name = query.name().to_ascii();
+
+        match query.query_type() {
+            RecordType::SRV if name == SERVICE => {
+                for srv in srv_records() {
+                    response.add_answer(Record::from_rdata(
+                        query.name().clone(),
+                        60,
+                        RData::SRV(srv),
+                    ));
+                }
+            }
+            RecordType::A => {
+                if let Some(ip) = host_ip(&name) {
+                    response.add_answer(Record::from_rdata(query.name().clone(), 60, RData::A(ip)));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    response
+}
+
+/// Answers queries on a loopback UDP port until the test ends
+async fn stub_nameserver() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let mut buf = [0; 4096];
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            let Ok(request) = Message::from_vec(&buf[..len]) else {
+                continue;
+            };
+            let response = answer(&request).to_bytes().unwrap();
+            socket.send_to(&response, from).await.unwrap();
+        }
+    });
+
+    addr
+}
+
+#[tokio::test]
+async fn srv_targets_are_tried_by_priority() {
+    let nameserver = stub_nameserver().await;
+    let config = ResolverConfig::from_parts(
+        None,
+        vec![],
+        NameServerConfigGroup::from_ips_clear(&[nameserver.ip()], nameserver.port(), true),
+    );
+    let resolver = TokioAsyncResolver::tokio(config, ResolverOpts::default()).unwrap();
+
+    let servers = Servers::from(SocketName::Srv(SERVICE.to_string()));
+    let failover = Failover::with_resolver(servers, FailoverOrder::Ordered, resolver);
+
+    let candidates = failover.candidates().await.unwrap();
+    let found: Vec<(SocketAddr, &str)> = candidates
+        .iter()
+        .map(|c| (c.addr, c.name.as_str()))
+        .collect();
+    assert_eq!(found.len(), 3, "{:?}", found);
+
+    let mut first_two = found[..2].to_vec();
+    first_two.sort();
+    assert_eq!(
+        first_two,
+        [
+            (
+                SocketAddr::new(IpAddr::from([127, 0, 0, 1]), 8765),
+                "auth1.test"
+            ),
+            (
+                SocketAddr::new(IpAddr::from([127, 0, 0, 2]), 8765),
+                "auth2.test"
+            ),
+        ]
+    );
+    assert_eq!(
+        found[2],
+        (
+            SocketAddr::new(IpAddr::from([127, 0, 0, 3]), 8766),
+            "auth3.test"
+        )
+    );
+}
@@ -0,0 +1,192 @@
+//! A small cache of lookups, for programs like `ls -l` that look the same few users up over and
+//! over. Answers that a user or group doesn't exist are cached too, usually for less time.
+//!
+//! Entries belong to the process that looked them up: a forked child starts with an empty cache,
+//! so it doesn't hand out answers it never checked for as long as its parent would have.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Key {
+    Name(String),
+    /// A uid or gid
+    Id(u32),
+}
+
+#[derive(Debug)]
+struct Cached<V> {
+    value: Option<V>,
+    expires: Instant,
+    /// When the entry was last used, counted in lookups, so the least recently used is evicted
+    used: u64,
+}
+
+#[derive(Debug)]
+pub struct Cache<V> {
+    entries: HashMap<Key, Cached<V>>,
+    lookups: u64,
+    /// Process the entries were looked up in
+    pid: u32,
+}
+
+impl<V> Default for Cache<V> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            lookups: 0,
+            pid: std::process::id(),
+        }
+    }
+}
+
+impl<V: Clone> Cache<V> {
+    /// The cached answer for `key`: `Some(None)` if it is known not to exist
+    pub fn get(&mut self, key: &Key) -> Option<Option<V>> {
+        self.forget_if_forked();
+        self.lookups += 1;
+
+        let entry = self.entries.get_mut(key)?;
+        if entry.expires <= Instant::now() {
+            self.entries.remove(key);
+            return None;
+        }
+
+        entry.used = self.lookups;
+        Some(entry.value.clone())
+    }
+
+    /// Remembers `value` for `ttl`, evicting the least recently used entry if `capacity` is
+    /// reached
+    pub fn insert(&mut self, key: Key, value: Option<V>, ttl: Duration, capacity: usize) {
+        self.forget_if_forked();
+        if capacity == 0 || ttl.is_zero() {
+            return;
+        }
+
+        if !self.entries.contains_key(&key) && self.entries.len() >= capacity {
+            let now = Instant::now();
+            self.entries.retain(|_, entry| entry.expires > now);
+
+            if self.entries.len() >= capacity {
+                let oldest = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.used)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    self.entries.remove(&oldest);
+                }
+            }
+        }
+
+        self.lookups += 1;
+        self.entries.insert(
+            key,
+            Cached {
+                value,
+                expires: Instant::now() + ttl,
+                used: self.lookups,
+            },
+        );
+    }
+
+    fn forget_if_forked(&mut self) {
+        self.clear_if_pid_changed(std::process::id());
+    }
+
+    /// Empties the cache if it is now used by process `pid` instead of the one that filled it
+    fn clear_if_pid_changed(&mut self, pid: u32) {
+        if pid != self.pid {
+            self.entries.clear();
+            self.pid = pid;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONG: Duration = Duration::from_secs(60);
+
+    fn name(name: &str) -> Key {
+        Key::Name(name.to_string())
+    }
+
+    #[test]
+    fn remembers_answers() {
+        let mut cache = Cache::default();
+        assert_eq!(cache.get(&name("alice")), None);
+
+        cache.insert(name("alice"), Some(1000), LONG, 10);
+        cache.insert(Key::Id(1001), None, LONG, 10);
+
+        assert_eq!(cache.get(&name("alice")), Some(Some(1000)));
+        assert_eq!(cache.get(&Key::Id(1001)), Some(None));
+        assert_eq!(cache.get(&Key::Id(1000)), None);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = Cache::default();
+        cache.insert(name("alice"), Some(1), LONG, 2);
+        cache.insert(name("bob"), Some(2), LONG, 2);
+
+        // Using alice leaves bob the oldest
+        cache.get(&name("alice"));
+        cache.insert(name("carol"), Some(3), LONG, 2);
+
+        assert_eq!(cache.get(&name("alice")), Some(Some(1)));
+        assert_eq!(cache.get(&name("bob")), None);
+        assert_eq!(cache.get(&name("carol")), Some(Some(3)));
+    }
+
+    #[test]
+    fn entries_expire() {
+        let mut cache = Cache::default();
+        cache.insert(name("alice"), Some(1), LONG, 10);
+        cache.insert(name("nobody"), None, Duration::from_millis(10), 10);
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(cache.get(&name("alice")), Some(Some(1)));
+        assert_eq!(cache.get(&name("nobody")), None);
+    }
+
+    #[test]
+    fn expired_entries_make_room_first() {
+        let mut cache = Cache::default();
+        cache.insert(name("alice"), Some(1), LONG, 2);
+        cache.insert(name("nobody"), None, Duration::from_millis(10), 2);
+
+        std::thread::sleep(Duration::from_millis(20));
+        cache.insert(name("bob"), Some(2), LONG, 2);
+        assert_eq!(cache.get(&name("alice")), Some(Some(1)));
+        assert_eq!(cache.get(&name("bob")), Some(Some(2)));
+    }
+
+    #[test]
+    fn off_when_empty_or_instant() {
+        let mut cache = Cache::default();
+        cache.insert(name("alice"), Some(1), LONG, 0);
+        cache.insert(name("bob"), Some(2), Duration::ZERO, 10);
+
+        assert_eq!(cache.get(&name("alice")), None);
+        assert_eq!(cache.get(&name("bob")), None);
+    }
+
+    #[test]
+    fn forgotten_after_fork() {
+        let mut cache = Cache::default();
+        cache.insert(name("alice"), Some(1), LONG, 10);
+
+        cache.clear_if_pid_changed(cache.pid);
+        assert_eq!(cache.get(&name("alice")), Some(Some(1)));
+
+        // As if this were a forked child
+        cache.clear_if_pid_changed(cache.pid + 1);
+        assert!(cache.entries.is_empty());
+    }
+}
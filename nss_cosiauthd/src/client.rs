@@ -1,26 +1,139 @@
 use std::{
-    sync::{Arc, Mutex},
-    time::{Duration, Instant},
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant, SystemTime},
 };
 
+use libcosiauthd::{AuthdClient, Failover, Group, ServerHello, Trust, User};
 use libnss::interop::Response;
-use tokio::time::sleep_until;
-use tracing::{error, info, warn};
-use trust_dns_resolver::TokioAsyncResolver;
+use tarpc::context::{self, Context};
+use tokio::{runtime::Runtime, time::sleep_until};
+use tracing::{debug, error, info, warn};
 
-use crate::{CFG, RT};
+use crate::{
+    cache::{Cache, Key},
+    NssConfig, CFG, RT,
+};
 
+/// The connection shared by every thread of the program. tarpc multiplexes their lookups over it,
+/// so the lock is only held to look at or replace the connection, never while waiting on a server.
 #[derive(Default)]
 pub struct ClientAccessControl {
-    client: Arc<Mutex<Option<AuthdClient>>>,
-    latest_ts: Arc<Mutex<Option<Instant>>>,
+    inner: Arc<Mutex<Inner>>,
+    users: Mutex<Cache<User>>,
+    groups: Mutex<Cache<Group>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Created on first use, since it needs the runtime
+    failover: Option<Arc<Failover>>,
+    connection: Option<Connection>,
+    /// Connections made so far, used to tell them apart
+    connections: u64,
+    /// The connection is closed by its reaper if no lookup happens before then
+    idle_until: Option<Instant>,
+    /// Lookups that failed in a row
+    failures: u32,
+    /// Lookups fail immediately until then, after too many failures
+    open_until: Option<Instant>,
+    /// A lookup is checking whether the servers are back after the cool-off
+    probing: bool,
+}
+
+#[derive(Clone)]
+struct Connection {
+    id: u64,
+    /// Server the client is connected to
+    addr: SocketAddr,
+    client: AuthdClient,
+    /// The server's answer to `hello` when connecting, so lookups know what it supports without
+    /// asking again
+    server: ServerHello,
+}
+
+/// Locks `mutex` even if a thread panicked while holding it. Nothing guarded here is ever left
+/// half updated, so the program can keep looking users up.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
 }
 
 impl ClientAccessControl {
+    /// Looks a user up with `f`, unless the cache knows the answer
+    pub fn user(
+        &self,
+        key: Key,
+        f: impl Fn(&mut AuthdClient, Context) -> Response<Option<User>>,
+    ) -> Response<Option<User>> {
+        self.cached(&self.users, key, f)
+    }
+
+    /// Looks a group up with `f`, unless the cache knows the answer
+    pub fn group(
+        &self,
+        key: Key,
+        f: impl Fn(&mut AuthdClient, Context) -> Response<Option<Group>>,
+    ) -> Response<Option<Group>> {
+        self.cached(&self.groups, key, f)
+    }
+
+    fn cached<V: Clone>(
+        &self,
+        cache: &Mutex<Cache<V>>,
+        key: Key,
+        f: impl Fn(&mut AuthdClient, Context) -> Response<Option<V>>,
+    ) -> Response<Option<V>> {
+        let Ok(cfg) = &*CFG else {
+            error!("Configuration unavialable");
+            return Response::Unavail;
+        };
+        if cfg.cache_size == 0 {
+            return self.with_client(f);
+        }
+
+        if let Some(value) = lock(cache).get(&key) {
+            debug!("nss_cosiauthd: ClientAccessControl: answered from cache");
+            return Response::Success(value);
+        }
+
+        let response = self.with_client(f);
+        if let Response::Success(value) = &response {
+            let ttl = match value {
+                Some(_) => cfg.cache_ttl_secs,
+                None => cfg.cache_negative_ttl_secs,
+            };
+            lock(cache).insert(key, value.clone(), Duration::from_secs(ttl), cfg.cache_size);
+        }
+
+        response
+    }
+
+    /// Runs `f` with a connected client and a context carrying the lookup's deadline, unless the
+    /// servers have been failing and are being given time to recover
     pub fn with_client<O>(
-        &mut self,
-        f: impl FnOnce(&mut AuthdClient) -> Response<O>,
+        &self,
+        f: impl Fn(&mut AuthdClient, Context) -> Response<O>,
+    ) -> Response<O> {
+        self.guarded(|connection, deadline| f(&mut connection.client, context_until(deadline)))
+    }
+
+    /// Like [`Self::with_client`], for listings that take a request per page. `f` also gets what
+    /// the server supports and the call timeout, which it gives each request in full.
+    pub fn with_listing<O>(
+        &self,
+        f: impl Fn(&mut AuthdClient, &ServerHello, Duration) -> Response<O>,
     ) -> Response<O> {
+        let Ok(cfg) = &*CFG else {
+            error!("Configuration unavialable");
+            return Response::Unavail;
+        };
+        let timeout = Duration::from_millis(cfg.call_timeout_ms);
+
+        self.guarded(|connection, _| f(&mut connection.client, &connection.server, timeout))
+    }
+
+    /// Runs `f` with a connection and the lookup's deadline, counting failures for the breaker
+    fn guarded<O>(&self, f: impl Fn(&mut Connection, Instant) -> Response<O>) -> Response<O> {
         let Ok(rt) = &*RT else {
             error!("Runtime unavialable");
             return Response::Unavail;
@@ -30,75 +143,229 @@ impl ClientAccessControl {
             return Response::Unavail;
         };
 
+        {
+            let mut inner = lock(&self.inner);
+            if let Some(open_until) = inner.open_until {
+                if Instant::now() < open_until || inner.probing {
+                    debug!("nss_cosiauthd: ClientAccessControl: servers failing, not trying");
+                    return Response::Unavail;
+                }
+                // After the cool-off the first lookup claims the probe, and the others keep
+                // failing immediately until it finishes
+                inner.probing = true;
+            }
+        }
+
+        let response = self.call(rt, cfg, f);
+
+        let mut inner = lock(&self.inner);
+        inner.probing = false;
+        if let Response::Unavail = response {
+            inner.failures += 1;
+            // Still at or past the limit if the probe failed, so the breaker opens again right
+            // away
+            if cfg.breaker_failures > 0 && inner.failures >= cfg.breaker_failures {
+                warn!(
+                    "nss_cosiauthd: ClientAccessControl: {} lookups failed in a row, failing lookups for {}s",
+                    inner.failures, cfg.breaker_cooloff_secs
+                );
+                inner.open_until =
+                    Some(Instant::now() + Duration::from_secs(cfg.breaker_cooloff_secs));
+            }
+        } else {
+            inner.failures = 0;
+            inner.open_until = None;
+        }
+
+        response
+    }
+
+    fn call<O>(
+        &self,
+        rt: &Runtime,
+        cfg: &NssConfig,
+        f: impl Fn(&mut Connection, Instant) -> Response<O>,
+    ) -> Response<O> {
+        let timeout = Duration::from_millis(cfg.call_timeout_ms);
+        let deadline = Instant::now() + timeout;
+
         let _guard = rt.enter();
-        let mut lts = self.latest_ts.lock().unwrap();
-        *lts = Some(std::time::Instant::now() + Duration::from_secs(30));
-        let cl = self.client.clone();
-        let latest_ts = self.latest_ts.clone();
-        tokio::spawn(async move {
-            loop {
-                let dur = latest_ts.lock().unwrap().unwrap_or(Instant::now()).into();
-                sleep_until(dur).await;
-                // make sure it wasn't moved forward while we were sleeping
-                if latest_ts.lock().unwrap().unwrap_or(Instant::now()) < Instant::now() {
-                    *cl.lock().unwrap() = None;
-                    warn!(
-                        "nss_cosiauthd: ClientAccessControl: client timed out, closing connection."
+        lock(&self.inner).idle_until =
+            Some(Instant::now() + Duration::from_secs(cfg.idle_timeout_secs));
+
+        let Some(failover) = self.failover(cfg) else {
+            return Response::Unavail;
+        };
+
+        let existing = {
+            let mut inner = lock(&self.inner);
+
+            // Reconnect if a server we prefer over the current one has recovered
+            if let Some(connection) = &inner.connection {
+                if failover.should_fall_forward(connection.addr) {
+                    info!(
+                        "nss_cosiauthd: ClientAccessControl: falling forward from {}",
+                        connection.addr
                     );
-                    break;
+                    inner.connection = None;
                 }
             }
-        });
 
-        let Ok(resolver) = TokioAsyncResolver::tokio_from_system_conf() else {
-            return Response::Unavail
+            inner.connection.clone()
         };
 
-        let final_sockaddr = match &cfg.host {
-            SocketName::Dns(name, port) => {
-                let Ok(ips) = rt.block_on(resolver.lookup_ip(name)) else {
-                    warn!("Failed to do DNS lookup");
-                    return Response::Unavail;
-                };
+        let reused = existing.is_some();
+        let mut connection = match existing {
+            Some(connection) => connection,
+            None => match self.connect(rt, cfg, &failover, deadline) {
+                Some(connection) => connection,
+                None => return Response::Unavail,
+            },
+        };
 
-                let Some(ip) = ips.iter().next() else {
-                    warn!("No ips were returned");
-                    return Response::Unavail;
-                };
+        info!("client ready");
+
+        let mut response = f(&mut connection, deadline);
+        if let Response::Unavail = response {
+            self.forget(&connection);
 
-                std::net::SocketAddr::new(ip, *port)
+            // A connection that sat idle may have been closed by the server (a restart, its idle
+            // timeout) without us noticing, so try a new one before blaming the server
+            if reused && Instant::now() < deadline {
+                debug!(
+                    "nss_cosiauthd: ClientAccessControl: connection to {} died, reconnecting",
+                    connection.addr
+                );
+                connection = match self.connect(rt, cfg, &failover, deadline) {
+                    Some(connection) => connection,
+                    None => return Response::Unavail,
+                };
+                response = f(&mut connection, deadline);
             }
-            SocketName::Addr(sa) => *sa,
-        };
+        }
 
-        info!(
-            "nss_cosiauthd: ClientAccessControl: connecting to {}",
-            final_sockaddr
-        );
+        // The server stopped answering, so fail over on the next call
+        if let Response::Unavail = response {
+            failover.failed(connection.addr);
+            self.forget(&connection);
+        }
 
-        let mut client = self.client.lock().unwrap();
-        if client.is_none() {
-            let Ok(cert) = std::fs::read(&cfg.cert) else {
-                error!("Failed to read cert");
-                return Response::Unavail;
-            };
+        response
+    }
 
-            let c = rt.block_on(authd::connect_client(
-                final_sockaddr,
-                &rustls::Certificate(cert),
-                "localhost",
-            ));
+    /// Drops `connection`, unless another thread already replaced it
+    fn forget(&self, connection: &Connection) {
+        let mut inner = lock(&self.inner);
+        if inner.connection.as_ref().map(|c| c.id) == Some(connection.id) {
+            inner.connection = None;
+        }
+    }
+
+    fn failover(&self, cfg: &NssConfig) -> Option<Arc<Failover>> {
+        let mut inner = lock(&self.inner);
 
-            match c {
-                Ok(c) => *client = Some(c),
-                Err(_) => return Response::Unavail,
+        if inner.failover.is_none() {
+            match Failover::new(cfg.host.clone(), cfg.failover) {
+                Ok(failover) => {
+                    let connect_timeout = Duration::from_millis(cfg.connect_timeout_ms);
+                    inner.failover = Some(Arc::new(failover.with_connect_timeout(connect_timeout)));
+                }
+                Err(err) => {
+                    error!("Failed to set up resolver: {}", err);
+                }
             }
         }
 
-        info!("client ready");
+        inner.failover.clone()
+    }
+
+    /// Connects without holding the lock. Threads that find no connection at the same time each
+    /// connect, and the first connection made is the one they all use.
+    fn connect(
+        &self,
+        rt: &Runtime,
+        cfg: &NssConfig,
+        failover: &Failover,
+        deadline: Instant,
+    ) -> Option<Connection> {
+        let trust = match Trust::load(&cfg.cert, cfg.pins.clone()) {
+            Ok(trust) => trust,
+            Err(err) => {
+                error!("Failed to load cert: {:#}", err);
+                return None;
+            }
+        };
+
+        let connect = failover.connect(&trust, cfg.server_name.as_deref(), cfg.wire_format);
+        let connected = match rt.block_on(tokio::time::timeout_at(deadline.into(), connect)) {
+            Ok(Ok(connected)) => connected,
+            Ok(Err(err)) => {
+                warn!("Failed to connect: {:#}", err);
+                return None;
+            }
+            Err(_) => {
+                warn!(
+                    "Failed to connect within {:?}",
+                    Duration::from_millis(cfg.call_timeout_ms)
+                );
+                return None;
+            }
+        };
+
+        let mut inner = lock(&self.inner);
+        if let Some(connection) = &inner.connection {
+            return Some(connection.clone());
+        }
+
+        info!(
+            "nss_cosiauthd: ClientAccessControl: connected to {}",
+            connected.addr
+        );
+        inner.connections += 1;
+        let connection = Connection {
+            id: inner.connections,
+            addr: connected.addr,
+            client: connected.client,
+            server: connected.server,
+        };
+        inner.connection = Some(connection.clone());
+        Self::reap(self.inner.clone(), connection.id);
+        Some(connection)
+    }
 
-        // SAFETY: `unwrap()` will never panic here because if client was `None` it would have been
-        // overwritten to `Some(c)` in the previous block or we returned Response::Unavail
-        f(client.as_mut().unwrap())
+    /// Closes the connection with id `id` once it has gone unused for the idle timeout. Ends early
+    /// if the connection is replaced.
+    fn reap(inner: Arc<Mutex<Inner>>, id: u64) {
+        tokio::spawn(async move {
+            loop {
+                let until = {
+                    let inner = lock(&inner);
+                    if inner.connection.as_ref().map(|c| c.id) != Some(id) {
+                        return;
+                    }
+                    inner.idle_until.unwrap_or_else(Instant::now)
+                };
+                sleep_until(until.into()).await;
+
+                // make sure it wasn't moved forward while we were sleeping
+                let mut inner = lock(&inner);
+                if inner.connection.as_ref().map(|c| c.id) != Some(id) {
+                    return;
+                }
+                if inner.idle_until.unwrap_or_else(Instant::now) <= Instant::now() {
+                    inner.connection = None;
+                    info!("nss_cosiauthd: ClientAccessControl: connection idle, closing it.");
+                    return;
+                }
+            }
+        });
     }
 }
+
+/// A context for a request that must be answered by `deadline`. Whatever connecting took comes out
+/// of the lookup's time.
+fn context_until(deadline: Instant) -> Context {
+    let mut ctx = context::current();
+    ctx.deadline = SystemTime::now() + deadline.saturating_duration_since(Instant::now());
+    ctx
+}
@@ -0,0 +1,89 @@
+//! Listing every user or group for `getpwent` and `getgrent`, a page at a time so no single
+//! message holds the whole database.
+
+use std::{
+    future::Future,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::bail;
+use libcosiauthd::{
+    protocol::capability, AuthdClient, Cursor, Group, Page, PageError, ServerHello, User,
+};
+use tarpc::{
+    client::RpcError,
+    context::{self, Context},
+};
+
+/// Entries asked for per page. The server may send fewer.
+const PAGE_SIZE: u32 = 500;
+
+/// Times a listing is started over when the database changes while it runs
+const ATTEMPTS: usize = 3;
+
+/// Lists every user. Servers that can't page, being older, send everything at once. Every request
+/// gets `timeout` to itself.
+pub async fn users(
+    client: &AuthdClient,
+    server: &ServerHello,
+    timeout: Duration,
+) -> anyhow::Result<Vec<User>> {
+    if !server.supports(capability::PAGING) {
+        return Ok(client.get_all_passwd(context_for(timeout)).await?);
+    }
+    all(timeout, |ctx, cursor| {
+        client.list_passwd(ctx, cursor, PAGE_SIZE)
+    })
+    .await
+}
+
+/// Lists every group, like [`users`]
+pub async fn groups(
+    client: &AuthdClient,
+    server: &ServerHello,
+    timeout: Duration,
+) -> anyhow::Result<Vec<Group>> {
+    if !server.supports(capability::PAGING) {
+        return Ok(client.get_all_groups(context_for(timeout)).await?);
+    }
+    all(timeout, |ctx, cursor| {
+        client.list_groups(ctx, cursor, PAGE_SIZE)
+    })
+    .await
+}
+
+/// A context for one request, due `timeout` from now
+fn context_for(timeout: Duration) -> Context {
+    let mut ctx = context::current();
+    ctx.deadline = SystemTime::now() + timeout;
+    ctx
+}
+
+/// Follows the cursors `list` hands out until the listing is complete, giving each page its own
+/// `timeout`
+async fn all<T, F, Fut>(timeout: Duration, list: F) -> anyhow::Result<Vec<T>>
+where
+    F: Fn(Context, Option<Cursor>) -> Fut,
+    Fut: Future<Output = Result<Result<Page<T>, PageError>, RpcError>>,
+{
+    for _ in 0..ATTEMPTS {
+        let mut entries = Vec::new();
+        let mut cursor = None;
+
+        loop {
+            match list(context_for(timeout), cursor).await? {
+                Ok(page) => {
+                    entries.extend(page.entries);
+                    cursor = page.next;
+                    if cursor.is_none() {
+                        return Ok(entries);
+                    }
+                }
+                Err(PageError::Expired) => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    bail!("the database kept changing while it was being listed")
+}
@@ -1,75 +1,97 @@
-use authd::types::GroupToNSS;
 use futures::executor::block_on;
+use libcosiauthd::{logging, GroupToNSS};
 use libnss::interop::Response;
-use tarpc::context;
-use tracing::{info, warn};
+use tracing::{error, info, warn};
 
-use crate::RPC;
+use crate::{cache::Key, enumerate, logged, CFG, RPC};
 
 pub struct AuthdGroup {}
 
 impl libnss::group::GroupHooks for AuthdGroup {
     fn get_all_entries() -> Response<Vec<libnss::group::Group>> {
-        let mut cl = RPC.lock().unwrap();
+        logged(|| {
+            info!("get_all_groups");
 
-        info!("get_all_groups");
-
-        cl.with_client(
-            |client| match block_on(client.get_all_groups(context::current())) {
-                Ok(groups) => {
-                    info!("get_all_groups success");
-                    Response::Success(groups.to_nss())
+            let cfg = match &*CFG {
+                Ok(cfg) => cfg,
+                Err(_) => {
+                    error!("get_all_groups missing config");
+                    return Response::Unavail;
                 }
-                Err(err) => {
-                    warn!("get_all_groups unavail {}", err);
-                    Response::Unavail
+            };
+
+            if !cfg.enumerate {
+                info!("get_all_groups disabled");
+                return Response::Success(Vec::new());
+            }
+
+            RPC.with_listing(|client, server, timeout| {
+                match block_on(enumerate::groups(client, server, timeout)) {
+                    Ok(groups) => {
+                        info!("get_all_groups success");
+                        Response::Success(groups.to_nss())
+                    }
+                    Err(err) => {
+                        warn!("get_all_groups unavail {:#}", err);
+                        Response::Unavail
+                    }
                 }
-            },
-        )
+            })
+        })
     }
 
     fn get_entry_by_gid(gid: libc::gid_t) -> Response<libnss::group::Group> {
-        let mut cl = RPC.lock().unwrap();
+        logged(|| {
+            info!("get_group_by_gid {}", gid);
 
-        info!("get_group_by_gid {}", gid);
+            let found = RPC.group(Key::Id(gid), |client, ctx| {
+                match block_on(client.get_group_by_gid(ctx, gid)) {
+                    Ok(group) => Response::Success(group),
+                    Err(err) => {
+                        warn!("get_group_by_gid {} Unavail {}", gid, err);
+                        Response::Unavail
+                    }
+                }
+            });
 
-        cl.with_client(
-            |client| match block_on(client.get_group_by_gid(context::current(), gid)) {
-                Ok(Some(group)) => {
+            match found {
+                Response::Success(Some(group)) => {
                     info!("get_group_by_gid {} Success", gid);
                     Response::Success(group.to_nss())
                 }
-                Ok(None) => {
+                Response::Success(None) => {
                     info!("get_group_by_gid {} NotFound", gid);
                     Response::NotFound
                 }
-                Err(err) => {
-                    warn!("get_group_by_gid {} Unavail {}", gid, err);
-                    Response::Unavail
-                }
-            },
-        )
+                _ => Response::Unavail,
+            }
+        })
     }
 
     fn get_entry_by_name(name: String) -> Response<libnss::group::Group> {
-        let mut cl = RPC.lock().unwrap();
+        logged(|| {
+            info!("get_group_by_name {}", logging::name(&name));
 
-        info!("get_group_by_name {}", name);
+            let found = RPC.group(Key::Name(name.clone()), |client, ctx| {
+                match block_on(client.get_group_by_name(ctx, name.clone())) {
+                    Ok(group) => Response::Success(group),
+                    Err(err) => {
+                        warn!("get_group_by_name {} Unavail {}", logging::name(&name), err);
+                        Response::Unavail
+                    }
+                }
+            });
 
-        cl.with_client(|client| {
-            match block_on(client.get_group_by_name(context::current(), name.clone())) {
-                Ok(Some(group)) => {
-                    info!("get_group_by_name {} Success", name);
+            match found {
+                Response::Success(Some(group)) => {
+                    info!("get_group_by_name {} Success", logging::name(&name));
                     Response::Success(group.to_nss())
                 }
-                Ok(None) => {
-                    info!("get_group_by_name {} NotFound", name);
+                Response::Success(None) => {
+                    info!("get_group_by_name {} NotFound", logging::name(&name));
                     Response::NotFound
                 }
-                Err(err) => {
-                    warn!("get_group_by_name {} Unavail {}", name, err);
-                    Response::Unavail
-                }
+                _ => Response::Unavail,
             }
         })
     }
@@ -1,12 +1,15 @@
+use libcosiauthd::{
+    logging::{self, LogConfig},
+    FailoverOrder, Pin, Servers, Shell, WireFormat,
+};
 use std::io;
-use std::sync::Mutex;
 use tokio::runtime::Runtime;
-use tracing::info;
-use tracing_subscriber::fmt::writer::MakeWriterExt;
+use tracing::{dispatcher, info, Dispatch};
 
 use crate::client::ClientAccessControl;
 use crate::group::AuthdGroup;
 use crate::passwd::AuthdPasswd;
+use crate::syslog::{Syslog, SyslogConfig};
 
 extern crate libc;
 #[macro_use]
@@ -14,33 +17,164 @@ extern crate lazy_static;
 #[macro_use]
 extern crate libnss;
 
+mod cache;
 mod client;
+mod enumerate;
 mod group;
 mod passwd;
+mod syslog;
 
-lazy_static! {
-    static ref RPC: Mutex<ClientAccessControl> = {
-        // Log all events to a rolling log file.
-        let logfile = tracing_appender::rolling::hourly("/tmp", "nss_cosiauthd.log");
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct NssConfig {
+    /// authd servers (or proxies) to fail over between
+    host: Servers,
+    #[serde(default)]
+    failover: FailoverOrder,
+    /// The server's certificate or its CA(s), DER or PEM
+    cert: String,
+    /// Name to check the server's certificate against, instead of the name it was found under
+    #[serde(default)]
+    server_name: Option<String>,
+    #[serde(default)]
+    pins: Vec<Pin>,
+    shells_root: String,
+    shells: Vec<Shell>,
+    home_root: String,
+    #[serde(default)]
+    wire_format: WireFormat,
+    /// Longest a lookup, or one page of a listing, may take, connecting included
+    #[serde(default = "default_call_timeout")]
+    call_timeout_ms: u64,
+    /// Longest connecting to one server may take before trying the next
+    #[serde(default = "default_connect_timeout")]
+    connect_timeout_ms: u64,
+    /// Failed lookups in a row after which lookups fail without trying the servers. 0 never stops
+    /// trying.
+    #[serde(default = "default_breaker_failures")]
+    breaker_failures: u32,
+    /// How long lookups keep failing immediately once `breaker_failures` is reached
+    #[serde(default = "default_breaker_cooloff")]
+    breaker_cooloff_secs: u64,
+    /// Whether programs may list every user and group (`getent passwd`). Turning this off keeps
+    /// programs that enumerate by accident from pulling in the whole database.
+    #[serde(default = "default_enumerate")]
+    enumerate: bool,
+    /// The connection to the server is closed after this long without a lookup
+    #[serde(default = "default_idle_timeout")]
+    idle_timeout_secs: u64,
+    /// Users and groups to remember lookups of. 0 disables the cache.
+    #[serde(default)]
+    cache_size: usize,
+    /// How long a found user or group is remembered
+    #[serde(default = "default_cache_ttl")]
+    cache_ttl_secs: u64,
+    /// How long it is remembered that a user or group doesn't exist
+    #[serde(default = "default_cache_negative_ttl")]
+    cache_negative_ttl_secs: u64,
+    #[serde(default)]
+    log: LogConfig,
+    #[serde(default)]
+    syslog: SyslogConfig,
+}
+
+fn default_call_timeout() -> u64 {
+    2000
+}
+
+fn default_connect_timeout() -> u64 {
+    1000
+}
+
+fn default_breaker_failures() -> u32 {
+    3
+}
+
+fn default_breaker_cooloff() -> u64 {
+    30
+}
+
+fn default_enumerate() -> bool {
+    true
+}
+
+fn default_idle_timeout() -> u64 {
+    30
+}
 
-        // Log `INFO` and above to stdout.
-        let stdout = std::io::stdout.with_max_level(tracing::Level::TRACE);
+fn default_cache_ttl() -> u64 {
+    30
+}
+
+fn default_cache_negative_ttl() -> u64 {
+    5
+}
+
+const CONFIG_PATH: &str = "/etc/auth/authd.toml";
+
+/// Overrides [`CONFIG_PATH`] when built with the `test-config` feature, so tests can load the
+/// module with a config of their own. The module runs inside setuid programs, so installed builds
+/// must never let the environment choose who to trust.
+#[cfg(feature = "test-config")]
+const CONFIG_PATH_ENV: &[u8] = b"NSS_COSIAUTHD_CONFIG\0";
+
+fn config_path() -> String {
+    #[cfg(feature = "test-config")]
+    {
+        // secure_getenv ignores the environment in setuid programs, should a test build ever be
+        // installed
+        let path = unsafe { libc::secure_getenv(CONFIG_PATH_ENV.as_ptr().cast()) };
+        if !path.is_null() {
+            let path = unsafe { std::ffi::CStr::from_ptr(path) };
+            return path.to_string_lossy().into_owned();
+        }
+    }
+    CONFIG_PATH.to_string()
+}
+
+fn load_config() -> anyhow::Result<NssConfig> {
+    let contents = std::fs::read_to_string(config_path())?;
+    let config = toml::from_str::<NssConfig>(&contents)?;
+    config
+        .host
+        .check_server_name(config.server_name.as_deref())?;
+    Ok(config)
+}
+
+/// Runs an NSS entry point with the module's own logging. The program's global subscriber, if it
+/// has one, belongs to the program and never sees the module's messages.
+pub(crate) fn logged<T>(f: impl FnOnce() -> T) -> T {
+    dispatcher::with_default(&LOG, f)
+}
+
+lazy_static! {
+    static ref RPC: ClientAccessControl = ClientAccessControl::default();
 
-        tracing_subscriber::fmt()
-            // Combine the stdout and log file `MakeWriter`s into one
-            // `MakeWriter` that writes to both
-            .with_writer(stdout.and(logfile))
-            .init();
+    static ref CFG: anyhow::Result<NssConfig> = load_config();
 
-        info!("logging ready");
+    /// Logs to syslog, never to stdout or stderr, which belong to whatever program is looking up
+    /// users, or to files others could read
+    static ref LOG: Dispatch = {
+        let (log, syslog) = match &*CFG {
+            Ok(cfg) => (cfg.log.clone(), cfg.syslog.clone()),
+            Err(_) => Default::default(),
+        };
 
-        Mutex::new(ClientAccessControl::default())
+        match logging::dispatch(&log, logging::Target::Library, Syslog::new(&syslog)) {
+            Ok(dispatch) => {
+                dispatcher::with_default(&dispatch, || info!("logging ready"));
+                dispatch
+            }
+            Err(_) => Dispatch::none(),
+        }
     };
 
     static ref RT: io::Result<Runtime> = tokio::runtime::Builder::new_multi_thread()
         .worker_threads(2)
         .enable_io()
         .enable_time()
+        // The runtime's threads are ours, so they log through the module's subscriber for as
+        // long as they run
+        .on_thread_start(|| std::mem::forget(dispatcher::set_default(&LOG)))
         .build();
 }
 
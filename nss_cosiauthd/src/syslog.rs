@@ -0,0 +1,220 @@
+//! Logging to syslog from inside other programs.
+//!
+//! Messages are sent straight to the `/dev/log` socket (which journald serves on systemd machines)
+//! rather than through `openlog(3)`, since that would change the ident and facility of the program
+//! we were loaded into. If the socket is missing or full, messages are dropped: the module must
+//! never fall back to the program's stdout or stderr, or to files others can read.
+
+use std::{io, os::unix::net::UnixDatagram, sync::Mutex, time::Instant};
+
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+const SOCKET: &str = "/dev/log";
+const IDENT: &str = "nss_cosiauthd";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Facility {
+    User,
+    Daemon,
+    Auth,
+    Authpriv,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl Facility {
+    fn code(self) -> u8 {
+        match self {
+            Facility::User => 1,
+            Facility::Daemon => 3,
+            Facility::Auth => 4,
+            Facility::Authpriv => 10,
+            Facility::Local0 => 16,
+            Facility::Local1 => 17,
+            Facility::Local2 => 18,
+            Facility::Local3 => 19,
+            Facility::Local4 => 20,
+            Facility::Local5 => 21,
+            Facility::Local6 => 22,
+            Facility::Local7 => 23,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct SyslogConfig {
+    /// `authpriv` by default, so lookups only land in logs readable by root
+    #[serde(default = "default_facility")]
+    pub facility: Facility,
+    /// Most messages a process sends per second, on average. Bursts of up to ten times this are
+    /// let through.
+    #[serde(default = "default_rate_limit")]
+    pub rate_limit: u32,
+}
+
+fn default_facility() -> Facility {
+    Facility::Authpriv
+}
+
+fn default_rate_limit() -> u32 {
+    10
+}
+
+impl Default for SyslogConfig {
+    fn default() -> Self {
+        Self {
+            facility: default_facility(),
+            rate_limit: default_rate_limit(),
+        }
+    }
+}
+
+/// A `MakeWriter` sending each log line to syslog as one message
+#[derive(Debug)]
+pub struct Syslog {
+    facility: u8,
+    socket: Mutex<Option<UnixDatagram>>,
+    limit: Mutex<RateLimit>,
+}
+
+impl Syslog {
+    pub fn new(config: &SyslogConfig) -> Self {
+        Self {
+            facility: config.facility.code(),
+            socket: Mutex::new(None),
+            limit: Mutex::new(RateLimit::new(config.rate_limit)),
+        }
+    }
+
+    fn send(&self, severity: u8, message: &[u8]) {
+        let Ok(mut limit) = self.limit.lock() else {
+            return;
+        };
+        let Some(suppressed) = limit.take() else {
+            return;
+        };
+        drop(limit);
+
+        let message = String::from_utf8_lossy(message);
+        let mut line = format!(
+            "<{}>{}[{}]: {}",
+            self.facility * 8 + severity,
+            IDENT,
+            std::process::id(),
+            message.trim_end()
+        );
+        if suppressed > 0 {
+            line.push_str(&format!(" ({} earlier messages suppressed)", suppressed));
+        }
+
+        let Ok(mut socket) = self.socket.lock() else {
+            return;
+        };
+        // Reconnect once in case syslog was restarted
+        for _ in 0..2 {
+            if socket.is_none() {
+                *socket = UnixDatagram::unbound()
+                    .and_then(|s| s.connect(SOCKET).map(|_| s))
+                    .ok();
+            }
+            match socket.as_ref().map(|s| s.send(line.as_bytes())) {
+                Some(Ok(_)) => return,
+                Some(Err(_)) => *socket = None,
+                None => return,
+            }
+        }
+    }
+}
+
+/// Syslog severity for a tracing level
+fn severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+pub struct Writer<'a> {
+    syslog: &'a Syslog,
+    severity: u8,
+}
+
+impl io::Write for Writer<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // The fmt layer writes each event in a single call
+        self.syslog.send(self.severity, buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = Writer<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Writer {
+            syslog: self,
+            severity: severity(&Level::INFO),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        Writer {
+            syslog: self,
+            severity: severity(meta.level()),
+        }
+    }
+}
+
+/// A token bucket, so a program looking up thousands of users doesn't flood syslog
+#[derive(Debug)]
+struct RateLimit {
+    per_sec: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+    suppressed: u64,
+}
+
+impl RateLimit {
+    fn new(per_sec: u32) -> Self {
+        let burst = per_sec as f64 * 10.0;
+        Self {
+            per_sec: per_sec as f64,
+            burst,
+            tokens: burst,
+            refilled: Instant::now(),
+            suppressed: 0,
+        }
+    }
+
+    /// Takes a token, returning how many messages were suppressed since the last one that got
+    /// through, or `None` if this one must be suppressed too
+    fn take(&mut self) -> Option<u64> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.burst);
+        self.refilled = now;
+
+        if self.tokens < 1.0 {
+            self.suppressed += 1;
+            return None;
+        }
+
+        self.tokens -= 1.0;
+        Some(std::mem::take(&mut self.suppressed))
+    }
+}
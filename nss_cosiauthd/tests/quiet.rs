@@ -0,0 +1,165 @@
+//! Checks the NSS module never writes to the stdout or stderr of the program it is loaded into,
+//! even when it can't read its config or reach a server.
+//!
+//! The test runs itself again as a child, which loads the built module the way glibc would and
+//! looks some users and groups up. The parent checks the child printed nothing. It needs the
+//! module built with the `test-config` feature, to point it at configs of its own:
+//!
+//! ```sh
+//! cargo test -p nss_cosiauthd --features test-config
+//! ```
+
+use std::{
+    ffi::CString,
+    fs,
+    os::raw::{c_char, c_int, c_void},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+const CHILD_ENV: &str = "NSS_COSIAUTHD_QUIET_CHILD";
+
+/// Where builds of the module with the `test-config` feature read their config from
+const CONFIG_ENV: &str = "NSS_COSIAUTHD_CONFIG";
+
+/// A config pointing at an address nothing answers on (TEST-NET-1, RFC 5737), with short timeouts
+/// so the lookups give up quickly, and debug logging so there is plenty to leak
+fn unreachable_config() -> String {
+    let cert = Path::new(env!("CARGO_MANIFEST_DIR")).join("../authd/tests/data/localhost.pem");
+    format!(
+        r#"host = "192.0.2.1:8765"
+server_name = "localhost"
+cert = {:?}
+shells_root = "/bin"
+shells = ["bash"]
+home_root = "/home"
+call_timeout_ms = 300
+connect_timeout_ms = 200
+
+[log]
+level = "debug"
+"#,
+        cert.display().to_string()
+    )
+}
+
+type GetByName =
+    unsafe extern "C" fn(*const c_char, *mut c_void, *mut c_char, usize, *mut c_int) -> c_int;
+type GetById = unsafe extern "C" fn(u32, *mut c_void, *mut c_char, usize, *mut c_int) -> c_int;
+
+fn main() {
+    if std::env::var_os(CHILD_ENV).is_some() {
+        child();
+        return;
+    }
+
+    let dir = std::env::temp_dir().join(format!("nss-cosiauthd-quiet-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let missing = dir.join("missing.toml");
+    let unreachable = dir.join("unreachable.toml");
+    fs::write(&unreachable, unreachable_config()).unwrap();
+
+    check_quiet("missing config", &missing);
+    check_quiet("unreachable server", &unreachable);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+/// Runs the child with the module reading `config`, checking it printed nothing
+fn check_quiet(case: &str, config: &Path) {
+    let output = Command::new(std::env::current_exe().unwrap())
+        .env(CHILD_ENV, "1")
+        .env(CONFIG_ENV, config)
+        .output()
+        .expect("running child");
+
+    assert!(
+        output.status.success(),
+        "{}: child failed: {:?}",
+        case,
+        output.status
+    );
+    assert!(
+        output.stdout.is_empty(),
+        "{}: module wrote to stdout: {:?}",
+        case,
+        String::from_utf8_lossy(&output.stdout)
+    );
+    assert!(
+        output.stderr.is_empty(),
+        "{}: module wrote to stderr: {:?}",
+        case,
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+/// target/<profile>/libnss_cosiauthd.so, next to the deps directory this test lives in
+fn module_path() -> PathBuf {
+    let exe = std::env::current_exe().unwrap();
+    exe.parent()
+        .and_then(|deps| deps.parent())
+        .unwrap()
+        .join("libnss_cosiauthd.so")
+}
+
+fn child() {
+    let path = CString::new(module_path().to_str().unwrap()).unwrap();
+
+    unsafe {
+        let handle = libc::dlopen(path.as_ptr(), libc::RTLD_NOW);
+        if handle.is_null() {
+            // Exiting with an error is fine, printing the reason isn't
+            std::process::exit(2);
+        }
+
+        let symbol = |name: &str| {
+            let name = CString::new(name).unwrap();
+            let symbol = libc::dlsym(handle, name.as_ptr());
+            if symbol.is_null() {
+                std::process::exit(3);
+            }
+            symbol
+        };
+        let getpwnam: GetByName = std::mem::transmute(symbol("_nss_cosiauthd_getpwnam_r"));
+        let getpwuid: GetById = std::mem::transmute(symbol("_nss_cosiauthd_getpwuid_r"));
+        let getgrnam: GetByName = std::mem::transmute(symbol("_nss_cosiauthd_getgrnam_r"));
+        let getgrgid: GetById = std::mem::transmute(symbol("_nss_cosiauthd_getgrgid_r"));
+
+        // Large enough for both struct passwd and struct group
+        let mut entry = [0u64; 16];
+        let mut buf = vec![0 as c_char; 4096];
+        let mut errno = 0;
+        let name = CString::new("nobody-here").unwrap();
+
+        for _ in 0..3 {
+            getpwnam(
+                name.as_ptr(),
+                entry.as_mut_ptr().cast(),
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut errno,
+            );
+            getpwuid(
+                60000,
+                entry.as_mut_ptr().cast(),
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut errno,
+            );
+            getgrnam(
+                name.as_ptr(),
+                entry.as_mut_ptr().cast(),
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut errno,
+            );
+            getgrgid(
+                60000,
+                entry.as_mut_ptr().cast(),
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut errno,
+            );
+        }
+    }
+}
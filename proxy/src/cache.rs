@@ -0,0 +1,115 @@
+use std::{
+    sync::RwLock,
+    time::{Duration, Instant, SystemTime},
+};
+
+use libcosiauthd::{protocol::capability, Changes, Replica};
+use tarpc::context;
+use tracing::{info, warn};
+
+use crate::upstream::Upstream;
+
+/// How long each `changes_since` long-poll is left open
+const LONG_POLL: Duration = Duration::from_secs(60);
+
+/// Delay before asking again after authd couldn't be reached
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Delay before checking again whether authd has been upgraded to one with a change feed
+const UNSUPPORTED_DELAY: Duration = Duration::from_secs(300);
+
+/// The proxy's warm copy of authd's database
+#[derive(Debug, Default)]
+pub struct Cache {
+    synced: RwLock<Option<Synced>>,
+}
+
+#[derive(Debug)]
+struct Synced {
+    replica: Replica,
+    at: Instant,
+}
+
+impl Cache {
+    /// Runs `f` against the cached database, or returns `None` if it hasn't been fetched yet
+    pub fn read<T>(&self, f: impl FnOnce(&Replica) -> T) -> Option<T> {
+        self.synced.read().unwrap().as_ref().map(|s| f(&s.replica))
+    }
+
+    /// Time since the cache was last confirmed to match authd
+    pub fn age(&self) -> Option<Duration> {
+        self.synced.read().unwrap().as_ref().map(|s| s.at.elapsed())
+    }
+
+    fn generation(&self) -> u64 {
+        self.read(|r| r.generation).unwrap_or_default()
+    }
+
+    fn apply(&self, changes: Changes) {
+        let mut synced = self.synced.write().unwrap();
+        let (mut replica, at) = match synced.take() {
+            Some(Synced { replica, at }) => (replica, Some(at)),
+            None => (Replica::default(), None),
+        };
+
+        match &changes {
+            Changes::Resync { generation, .. } => info!("Resynced to generation {}", generation),
+            Changes::Diff(diff) if !diff.is_empty() => info!(
+                "Updated to generation {}: {} users and {} groups changed",
+                diff.generation,
+                diff.users.len() + diff.removed_users.len(),
+                diff.groups.len() + diff.removed_groups.len()
+            ),
+            Changes::Diff(_) => {}
+        }
+
+        if replica.apply(changes) {
+            *synced = Some(Synced {
+                replica,
+                at: Instant::now(),
+            });
+        } else {
+            // Out of step with authd, so ask for everything next time. Until then the cache keeps
+            // the age of the last changes that did apply, and isn't used at all if none have.
+            warn!("Received changes that don't follow the cache, resyncing");
+            replica.generation = 0;
+            *synced = at.map(|at| Synced { replica, at });
+        }
+    }
+
+    /// Keeps the cache up to date by long-polling authd for changes. Never returns.
+    pub async fn follow(&self, upstream: &Upstream) {
+        loop {
+            let connected = match upstream.client().await {
+                Ok(connected) => connected,
+                Err(err) => {
+                    warn!("Connecting to authd failed: {:#}", err);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                    continue;
+                }
+            };
+
+            // Without a change feed every request is forwarded instead
+            if !connected.server.supports(capability::CHANGES) {
+                warn!(
+                    "authd at {} has no change feed, not caching",
+                    connected.addr
+                );
+                tokio::time::sleep(UNSUPPORTED_DELAY).await;
+                continue;
+            }
+
+            let mut ctx = context::current();
+            ctx.deadline = SystemTime::now() + LONG_POLL;
+
+            match connected.client.changes_since(ctx, self.generation()).await {
+                Ok(changes) => self.apply(changes),
+                Err(err) => {
+                    warn!("Fetching changes failed: {}", err);
+                    upstream.failed(connected.addr).await;
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+}
@@ -1,72 +1,89 @@
+mod cache;
 mod rpc;
+mod upstream;
 
-use libcosiauthd::{Authd, AuthdClient, Shell, SocketName};
-use std::{fs, path::Path, sync::Arc};
-use stubborn_io::StubbornTcpStream;
-use tarpc::{
-    serde_transport::Transport,
-    server::{BaseChannel, Channel},
-    tokio_serde::formats::Json,
+use libcosiauthd::{
+    logging::{self, LogConfig},
+    wire, Authd, FailoverOrder, Pin, Servers, Shell, WireFormat,
 };
-use tokio::net::{ToSocketAddrs, UnixListener};
-use tokio_rustls::TlsConnector;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tarpc::server::{BaseChannel, Channel};
+use tokio::net::UnixListener;
 use tracing::{info, warn};
-use tracing_subscriber::FmtSubscriber;
 
-use crate::rpc::ProxySession;
+use crate::{rpc::ProxySession, upstream::Upstream};
+
+/// Bounds for the delay after a failed `accept`, e.g. when out of file descriptors
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
 
 #[derive(Debug, serde::Deserialize)]
 pub(crate) struct ProxyConfig {
-    host: SocketName,
+    /// authd servers to fail over between
+    host: Servers,
+    #[serde(default)]
+    failover: FailoverOrder,
+    /// authd's certificate or its CA(s), DER or PEM
     cert: String,
+    /// Name to check authd's certificate against, instead of the name it was found under
+    #[serde(default)]
+    server_name: Option<String>,
+    #[serde(default)]
+    pins: Vec<Pin>,
     shells_root: String,
     shells: Vec<Shell>,
     home_root: String,
+    /// Unix socket the proxy listens on
+    #[serde(default = "default_socket")]
+    socket: PathBuf,
+    /// How long a client gets to say which wire format it speaks
+    #[serde(default = "default_handshake_timeout")]
+    handshake_timeout_secs: u64,
+    /// Format used when talking to authd. Clients of the proxy pick their own.
+    #[serde(default)]
+    wire_format: WireFormat,
+    /// Most users or groups sent in one page of `list_passwd` or `list_groups`
+    #[serde(default = "default_max_page_size")]
+    max_page_size: u32,
+    /// Most keys looked up by one of the batch lookups
+    #[serde(default = "default_max_batch_size")]
+    max_batch_size: u32,
+    #[serde(default)]
+    log: LogConfig,
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    // a builder for `FmtSubscriber`.
-    let subscriber = FmtSubscriber::builder()
-        // completes the builder.
-        .finish();
+fn default_socket() -> PathBuf {
+    PathBuf::from("/run/cosiauthd.sock")
+}
+
+fn default_handshake_timeout() -> u64 {
+    10
+}
 
-    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+fn default_max_page_size() -> u32 {
+    1000
+}
+
+fn default_max_batch_size() -> u32 {
+    1000
+}
 
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
     let contents = fs::read_to_string("/etc/auth/authd.toml").expect("read config");
     let config = toml::from_str::<ProxyConfig>(&contents).expect("parse config");
+    config
+        .host
+        .check_server_name(config.server_name.as_deref())?;
 
-    Ok(())
-}
+    logging::init(&config.log, logging::Target::Daemon, std::io::stdout)?;
 
-/// Connect to authd over TLS, already knowing + trusting its certificate (if we don't get MITM).
-///
-/// The server_name is used for SNI. Setting it to localhost is fine for testing.
-async fn connect_client<A: ToSocketAddrs + Unpin + Clone + Send + Sync + 'static>(
-    addr: A,
-    cert: &rustls::Certificate,
-    server_name: &str,
-) -> anyhow::Result<AuthdClient> {
-    let tcp_stream = StubbornTcpStream::connect(addr)
-        .await
-        .expect("failed to create StubbornTcpStream connection");
-
-    let mut roots = rustls::RootCertStore::empty();
-    roots.add(cert).unwrap();
-
-    let config = rustls::ClientConfig::builder()
-        .with_safe_defaults()
-        .with_root_certificates(roots)
-        .with_no_client_auth();
-
-    let connector = TlsConnector::from(Arc::new(config));
-    let servername = rustls::ServerName::try_from(server_name)?;
-    let transport = Transport::from((
-        connector.connect(servername, tcp_stream).await?,
-        tarpc::tokio_serde::formats::Json::default(),
-    ));
-
-    Ok(AuthdClient::new(tarpc::client::Config::default(), transport).spawn())
+    listen_unix(config.socket.clone(), config).await
 }
 
 /// Serve a TARPC server waiting for a unix socket
@@ -74,30 +91,56 @@ async fn listen_unix<P>(socket: P, config: ProxyConfig) -> anyhow::Result<()>
 where
     P: AsRef<Path>,
 {
-    // Connect to authd
-    let cert = rustls::Certificate(config.cert.into_bytes());
-    let addr = &config.host.to_socket_addr();
+    let handshake_timeout = Duration::from_secs(config.handshake_timeout_secs);
 
-    let client = connect_client(config.host, &cert, "localhost").await?;
+    // Connections to authd are made as they are needed
+    let upstream = Upstream::new(&config)?;
+    let session = ProxySession::new(Arc::new(config), upstream);
 
     let listener = UnixListener::bind(socket)?;
 
     info!("Listening to {:?}", listener.local_addr().unwrap());
 
+    let mut backoff = MIN_ACCEPT_BACKOFF;
     loop {
-        let session = session.clone();
+        let (session, mut closing) = session.connection();
 
         match listener.accept().await {
             Ok((stream, addr)) => {
+                backoff = MIN_ACCEPT_BACKOFF;
                 info!("New connection from {:?}", addr);
 
-                let tport = tarpc::serde_transport::Transport::from((stream, Json::default()));
-                let channel = BaseChannel::with_defaults(tport);
-
-                channel.execute(session.serve()).await;
+                tokio::spawn(async move {
+                    let negotiate = wire::negotiate(stream);
+                    let (stream, format) =
+                        match tokio::time::timeout(handshake_timeout, negotiate).await {
+                            Ok(Ok(negotiated)) => negotiated,
+                            Ok(Err(err)) => {
+                                warn!("Negotiating wire format failed {:?}", err);
+                                return;
+                            }
+                            Err(_) => {
+                                warn!("Negotiating wire format with {:?} timed out", addr);
+                                return;
+                            }
+                        };
+
+                    let tport = wire::transport(stream, format);
+                    let channel = BaseChannel::with_defaults(tport);
+
+                    // Dropping the channel hangs up on the client
+                    tokio::select! {
+                        _ = channel.execute(session.serve()) => {}
+                        _ = closing.changed() => {
+                            warn!("Hanging up on {:?}, authd can't be reached", addr);
+                        }
+                    }
+                });
             }
             Err(err) => {
-                warn!("Connection failed {:?}", err)
+                warn!("Connection failed, retrying in {:?}: {:?}", backoff, err);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
             }
         }
     }
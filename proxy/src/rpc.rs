@@ -1,41 +1,477 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
-use libcosiauthd::{Authd, Group, User, AuthdClient};
-use tarpc::context::Context;
+use libcosiauthd::{
+    batch::find_many,
+    page,
+    protocol::{self, capability},
+    AttributeError, Attributes, Authd, AuthdClient, Changes, Cursor, Diff, Group, Hello, Page,
+    PageError, ProtocolError, Replica, Search, SearchError, ServerHello, Status, UpstreamStatus,
+    User,
+};
+use tarpc::{client::RpcError, context::Context};
+use tokio::sync::watch;
+use tracing::warn;
 
-use crate::ProxyConfig;
+use crate::{cache::Cache, upstream::Upstream, ProxyConfig};
+
+/// Longest a `changes_since` call is held open while authd can't be reached
+const MAX_LONG_POLL: Duration = Duration::from_secs(300);
 
 #[derive(Debug, Clone)]
 pub struct ProxySession {
     config: Arc<ProxyConfig>,
-    client: Arc<Mutex<AuthdClient>>,
+    upstream: Arc<Upstream>,
+    started: Instant,
+    /// Lookups are answered from here once it has been filled
+    cache: Arc<Cache>,
+    /// Set to hang up on the client this session serves
+    hang_up: Arc<watch::Sender<bool>>,
+}
+
+impl ProxySession {
+    /// Creates a session and starts keeping its cache up to date in the background
+    pub fn new(config: Arc<ProxyConfig>, upstream: Arc<Upstream>) -> Self {
+        let session = Self {
+            config,
+            upstream,
+            started: Instant::now(),
+            cache: Arc::new(Cache::default()),
+            hang_up: Arc::new(watch::channel(false).0),
+        };
+
+        let cache = session.cache.clone();
+        let upstream = session.upstream.clone();
+        tokio::spawn(async move { cache.follow(&upstream).await });
+
+        session
+    }
+
+    /// A session for one client connection, and what is told when it should be closed
+    pub fn connection(&self) -> (Self, watch::Receiver<bool>) {
+        let (hang_up, closing) = watch::channel(false);
+        let session = Self {
+            hang_up: Arc::new(hang_up),
+            ..self.clone()
+        };
+        (session, closing)
+    }
+
+    /// Sends a request to authd, or returns `None` if it can't be reached. Methods newer than
+    /// protocol version 1 name the `capability` authd must advertise for them, since servers that
+    /// don't know a method hang up on it.
+    async fn forward<T, F, Fut>(
+        &self,
+        method: &str,
+        capability: Option<&str>,
+        request: F,
+    ) -> Option<T>
+    where
+        F: FnOnce(AuthdClient) -> Fut,
+        Fut: Future<Output = Result<T, RpcError>>,
+    {
+        let connected = match self.upstream.client().await {
+            Ok(connected) => connected,
+            Err(err) => {
+                warn!("{} failed, no upstream: {:#}", method, err);
+                return None;
+            }
+        };
+
+        if let Some(capability) = capability {
+            if !connected.server.supports(capability) {
+                warn!(
+                    "{} failed, authd at {} is too old for it",
+                    method, connected.addr
+                );
+                return None;
+            }
+        }
+
+        match request(connected.client).await {
+            Ok(value) => Some(value),
+            Err(err) => {
+                warn!("{} failed upstream: {}", method, err);
+                self.upstream.failed(connected.addr).await;
+                None
+            }
+        }
+    }
+
+    /// Forwards a lookup, which has no way to report an error. Answering with nothing would tell
+    /// the client the user or group doesn't exist, so if authd can't be reached the connection is
+    /// closed instead. The client then fails straight away with `Disconnected`, which NSS reports
+    /// as unavailable rather than not found.
+    async fn lookup<T, F, Fut>(&self, method: &str, capability: Option<&str>, request: F) -> T
+    where
+        T: Default,
+        F: FnOnce(AuthdClient) -> Fut,
+        Fut: Future<Output = Result<T, RpcError>>,
+    {
+        match self.forward(method, capability, request).await {
+            Some(value) => value,
+            None => {
+                self.hang_up.send_replace(true);
+                // Nobody reads the answer once the connection is gone
+                self.hang_up.closed().await;
+                T::default()
+            }
+        }
+    }
 }
 
 #[tarpc::server]
 impl Authd for ProxySession {
-    async fn get_all_groups(self, _ctx: Context) -> Vec<Group> {
-        let client = self.client.lock().unwrap();
+    async fn hello(self, _ctx: Context, client: Hello) -> Result<ServerHello, ProtocolError> {
+        if let Err(err) = protocol::check_client(&client) {
+            warn!("refusing {}: {}", client.software, err);
+            return Err(err);
+        }
+
+        Ok(ServerHello::new(concat!(
+            "proxy/",
+            env!("CARGO_PKG_VERSION")
+        )))
+    }
+
+    async fn get_all_groups(self, ctx: Context) -> Vec<Group> {
+        if let Some(cached) = self.cache.read(|r| r.groups.clone()) {
+            return cached;
+        }
+
+        self.lookup("get_all_groups", None, |client| async move {
+            client.get_all_groups(ctx).await
+        })
+        .await
+    }
 
-        todo!()
+    async fn get_group_by_name(self, ctx: Context, name: String) -> Option<Group> {
+        if let Some(cached) = self
+            .cache
+            .read(|r| r.groups.iter().find(|g| g.name == name).cloned())
+        {
+            return cached;
+        }
+
+        self.lookup("get_group_by_name", None, |client| async move {
+            client.get_group_by_name(ctx, name).await
+        })
+        .await
     }
 
-    async fn get_group_by_name(self, _ctx: Context, name: String) -> Option<Group> {
-        todo!()
+    async fn get_group_by_gid(self, ctx: Context, gid: u32) -> Option<Group> {
+        if let Some(cached) = self
+            .cache
+            .read(|r| r.groups.iter().find(|g| g.gid == gid).cloned())
+        {
+            return cached;
+        }
+
+        self.lookup("get_group_by_gid", None, |client| async move {
+            client.get_group_by_gid(ctx, gid).await
+        })
+        .await
     }
 
-    async fn get_group_by_gid(self, _ctx: Context, gid: u32) -> Option<Group> {
-        todo!()
+    async fn get_all_passwd(self, ctx: Context) -> Vec<User> {
+        if let Some(cached) = self.cache.read(|r| r.users.clone()) {
+            return cached;
+        }
+
+        self.lookup("get_all_passwd", None, |client| async move {
+            client.get_all_passwd(ctx).await
+        })
+        .await
     }
 
-    async fn get_all_passwd(self, _ctx: Context) -> Vec<User> {
-        todo!()
+    async fn get_passwd_by_name(self, ctx: Context, name: String) -> Option<User> {
+        if let Some(cached) = self
+            .cache
+            .read(|r| r.users.iter().find(|u| u.name == name).cloned())
+        {
+            return cached;
+        }
+
+        self.lookup("get_passwd_by_name", None, |client| async move {
+            client.get_passwd_by_name(ctx, name).await
+        })
+        .await
     }
 
-    async fn get_passwd_by_name(self, _ctx: Context, name: String) -> Option<User> {
-        todo!()
+    async fn get_passwd_by_uid(self, ctx: Context, uid: u32) -> Option<User> {
+        if let Some(cached) = self
+            .cache
+            .read(|r| r.users.iter().find(|u| u.id == uid).cloned())
+        {
+            return cached;
+        }
+
+        self.lookup("get_passwd_by_uid", None, |client| async move {
+            client.get_passwd_by_uid(ctx, uid).await
+        })
+        .await
+    }
+
+    async fn get_groups_by_name(self, ctx: Context, names: Vec<String>) -> Vec<Option<Group>> {
+        let max = self.config.max_batch_size;
+        let mut names = names;
+        names.truncate(max as usize);
+        if let Some(cached) = self.cache.read(|r| {
+            find_many(
+                &r.groups,
+                |g| g.name.as_str(),
+                names.iter().map(String::as_str),
+                max,
+            )
+        }) {
+            return cached;
+        }
+
+        self.lookup(
+            "get_groups_by_name",
+            Some(capability::BATCH),
+            |client| async move { client.get_groups_by_name(ctx, names).await },
+        )
+        .await
+    }
+
+    async fn get_groups_by_gid(self, ctx: Context, gids: Vec<u32>) -> Vec<Option<Group>> {
+        let max = self.config.max_batch_size;
+        let mut gids = gids;
+        gids.truncate(max as usize);
+        if let Some(cached) = self
+            .cache
+            .read(|r| find_many(&r.groups, |g| g.gid, gids.iter().copied(), max))
+        {
+            return cached;
+        }
+
+        self.lookup(
+            "get_groups_by_gid",
+            Some(capability::BATCH),
+            |client| async move { client.get_groups_by_gid(ctx, gids).await },
+        )
+        .await
     }
 
-    async fn get_passwd_by_uid(self, _ctx: Context, uid: u32) -> Option<User> {
-        todo!()
+    async fn get_passwds_by_name(self, ctx: Context, names: Vec<String>) -> Vec<Option<User>> {
+        let max = self.config.max_batch_size;
+        let mut names = names;
+        names.truncate(max as usize);
+        if let Some(cached) = self.cache.read(|r| {
+            find_many(
+                &r.users,
+                |u| u.name.as_str(),
+                names.iter().map(String::as_str),
+                max,
+            )
+        }) {
+            return cached;
+        }
+
+        self.lookup(
+            "get_passwds_by_name",
+            Some(capability::BATCH),
+            |client| async move { client.get_passwds_by_name(ctx, names).await },
+        )
+        .await
+    }
+
+    async fn get_passwds_by_uid(self, ctx: Context, uids: Vec<u32>) -> Vec<Option<User>> {
+        let max = self.config.max_batch_size;
+        let mut uids = uids;
+        uids.truncate(max as usize);
+        if let Some(cached) = self
+            .cache
+            .read(|r| find_many(&r.users, |u| u.id, uids.iter().copied(), max))
+        {
+            return cached;
+        }
+
+        self.lookup(
+            "get_passwds_by_uid",
+            Some(capability::BATCH),
+            |client| async move { client.get_passwds_by_uid(ctx, uids).await },
+        )
+        .await
+    }
+
+    async fn list_groups(
+        self,
+        ctx: Context,
+        cursor: Option<Cursor>,
+        limit: u32,
+    ) -> Result<Page<Group>, PageError> {
+        let max = self.config.max_page_size;
+        if let Some(cached) = self
+            .cache
+            .read(|r| page_cached(r, &r.groups, cursor, limit, max))
+        {
+            return cached;
+        }
+
+        self.forward(
+            "list_groups",
+            Some(capability::PAGING),
+            |client| async move { client.list_groups(ctx, cursor, limit.min(max)).await },
+        )
+        .await
+        .unwrap_or(Err(PageError::Unavailable))
+    }
+
+    async fn list_passwd(
+        self,
+        ctx: Context,
+        cursor: Option<Cursor>,
+        limit: u32,
+    ) -> Result<Page<User>, PageError> {
+        let max = self.config.max_page_size;
+        if let Some(cached) = self
+            .cache
+            .read(|r| page_cached(r, &r.users, cursor, limit, max))
+        {
+            return cached;
+        }
+
+        self.forward(
+            "list_passwd",
+            Some(capability::PAGING),
+            |client| async move { client.list_passwd(ctx, cursor, limit.min(max)).await },
+        )
+        .await
+        .unwrap_or(Err(PageError::Unavailable))
+    }
+
+    // Searches and attributes always go to authd, since only it knows the admin tokens
+
+    async fn search_users(
+        self,
+        ctx: Context,
+        token: String,
+        query: Search,
+    ) -> Result<Vec<User>, SearchError> {
+        self.forward(
+            "search_users",
+            Some(capability::SEARCH),
+            |client| async move { client.search_users(ctx, token, query).await },
+        )
+        .await
+        .unwrap_or(Err(SearchError::Unavailable))
+    }
+
+    async fn search_groups(
+        self,
+        ctx: Context,
+        token: String,
+        query: Search,
+    ) -> Result<Vec<Group>, SearchError> {
+        self.forward(
+            "search_groups",
+            Some(capability::SEARCH),
+            |client| async move { client.search_groups(ctx, token, query).await },
+        )
+        .await
+        .unwrap_or(Err(SearchError::Unavailable))
+    }
+
+    async fn get_user_attributes(
+        self,
+        ctx: Context,
+        token: String,
+        name: String,
+    ) -> Result<Option<Attributes>, AttributeError> {
+        self.forward(
+            "get_user_attributes",
+            Some(capability::ATTRIBUTES),
+            |client| async move { client.get_user_attributes(ctx, token, name).await },
+        )
+        .await
+        .unwrap_or(Err(AttributeError::Unavailable))
+    }
+
+    async fn get_group_attributes(
+        self,
+        ctx: Context,
+        token: String,
+        name: String,
+    ) -> Result<Option<Attributes>, AttributeError> {
+        self.forward(
+            "get_group_attributes",
+            Some(capability::ATTRIBUTES),
+            |client| async move { client.get_group_attributes(ctx, token, name).await },
+        )
+        .await
+        .unwrap_or(Err(AttributeError::Unavailable))
+    }
+
+    async fn status(self, ctx: Context) -> Status {
+        let (database, replication) = self
+            .forward(
+                "status",
+                None,
+                |client| async move { client.status(ctx).await },
+            )
+            .await
+            .map_or((None, None), |status| (status.database, status.replication));
+        let active = self.upstream.active();
+
+        Status {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            database,
+            upstream: Some(UpstreamStatus {
+                host: active.map_or_else(|| self.config.host.to_string(), |addr| addr.to_string()),
+                connected: active.is_some(),
+                cache_age_secs: self.cache.age().map(|age| age.as_secs()),
+            }),
+            replication,
+        }
+    }
+
+    async fn changes_since(self, ctx: Context, generation: u64) -> Changes {
+        let changes = self
+            .forward(
+                "changes_since",
+                Some(capability::CHANGES),
+                |client| async move { client.changes_since(ctx, generation).await },
+            )
+            .await;
+        if let Some(changes) = changes {
+            return changes;
+        }
+
+        // Hold the long-poll like authd would, so followers don't ask again in a tight loop for
+        // as long as authd is unreachable, then report no changes a little before the deadline
+        let wait = ctx
+            .deadline
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+            .saturating_sub(Duration::from_secs(1))
+            .min(MAX_LONG_POLL);
+        tokio::time::sleep(wait).await;
+
+        Changes::Diff(Diff {
+            from: generation,
+            generation,
+            ..Default::default()
+        })
+    }
+}
+
+/// Pages through the cache's copy of `entries`. A cursor from another generation can't be
+/// continued, since the cache has moved on from it.
+fn page_cached<T: Clone>(
+    replica: &Replica,
+    entries: &[T],
+    cursor: Option<Cursor>,
+    limit: u32,
+    max: u32,
+) -> Result<Page<T>, PageError> {
+    match cursor {
+        Some(cursor) if cursor.generation != replica.generation => Err(PageError::Expired),
+        _ => Ok(page::page(entries, replica.generation, cursor, limit, max)),
     }
 }
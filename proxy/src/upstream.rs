@@ -0,0 +1,86 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use libcosiauthd::{Connected, Failover, Trust, WireFormat};
+use tracing::{info, warn};
+
+use crate::ProxyConfig;
+
+/// The proxy's connection to authd, failing over between the configured servers
+#[derive(Debug)]
+pub struct Upstream {
+    failover: Failover,
+    trust: Trust,
+    server_name: Option<String>,
+    format: WireFormat,
+    current: tokio::sync::Mutex<Option<Connected>>,
+    /// The server currently connected to, for `status`
+    active: Mutex<Option<SocketAddr>>,
+}
+
+impl Upstream {
+    pub fn new(config: &ProxyConfig) -> anyhow::Result<Arc<Self>> {
+        Ok(Arc::new(Self {
+            failover: Failover::new(config.host.clone(), config.failover)?,
+            trust: Trust::load(&config.cert, config.pins.clone())?,
+            server_name: config.server_name.clone(),
+            format: config.wire_format,
+            current: Default::default(),
+            active: Default::default(),
+        }))
+    }
+
+    /// The server currently connected to, if any
+    pub fn active(&self) -> Option<SocketAddr> {
+        *self.active.lock().unwrap()
+    }
+
+    /// Returns a client for the current server, first connecting if there is none or falling
+    /// forward if a preferred server has recovered
+    pub async fn client(&self) -> anyhow::Result<Connected> {
+        let mut current = self.current.lock().await;
+
+        if let Some(connected) = &*current {
+            if !self.failover.should_fall_forward(connected.addr) {
+                return Ok(connected.clone());
+            }
+            info!("Trying to fall forward from {}", connected.addr);
+        }
+
+        match self
+            .failover
+            .connect(&self.trust, self.server_name.as_deref(), self.format)
+            .await
+        {
+            Ok(connected) => {
+                info!(
+                    "Connected to {} ({}, protocol version {})",
+                    connected.addr, connected.server.software, connected.server.protocol_version
+                );
+                *current = Some(connected.clone());
+                *self.active.lock().unwrap() = Some(connected.addr);
+                Ok(connected)
+            }
+            Err(err) => {
+                *current = None;
+                *self.active.lock().unwrap() = None;
+                Err(err)
+            }
+        }
+    }
+
+    /// Drops the connection to `addr` after a request to it failed, so the next request fails
+    /// over to another server
+    pub async fn failed(&self, addr: SocketAddr) {
+        let mut current = self.current.lock().await;
+
+        if matches!(&*current, Some(current) if current.addr == addr) {
+            warn!("Lost connection to {}", addr);
+            self.failover.failed(addr);
+            *current = None;
+            *self.active.lock().unwrap() = None;
+        }
+    }
+}